@@ -7,14 +7,19 @@ use crate::task::PupTask;
 use crate::utils::path;
 use crate::utils::path::exists;
 use crate::utils::path::join;
+use crate::utils::secrets::PupSecrets;
 use crate::worker::PupWorker;
 use base_logging::Level;
 use std::collections::HashMap;
+use std::env;
 use std::fs::canonicalize;
 use std::path::{Path, PathBuf};
 
 mod global_env;
 
+/// The default limit on how deeply steps may nest before loading gives up.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
 #[derive(Clone, Debug)]
 pub struct PupContext {
     /// The global config that exists as a root for the whole process
@@ -23,32 +28,42 @@ pub struct PupContext {
     /// The config file passed to each worker.
     pub env: HashMap<String, String>,
 
-    /// The root folder for the set of workers which are available.
-    pub workers: PathBuf,
+    /// The root folders to search for workers, in order.
+    pub workers: Vec<PathBuf>,
+
+    /// If a worker isn't in any of the worker folders, look for it on the PATH.
+    pub workers_from_path: bool,
+
+    /// The root folders to search for tasks, in order; the first root with a task wins.
+    pub tasks: Vec<PathBuf>,
+
+    /// The maximum nesting depth of steps when loading a task tree.
+    pub max_depth: usize,
 
-    /// The root folder for the set of tasks which are available.
-    pub tasks: PathBuf,
+    /// Fail to load any step that uses an undefined variable in a template.
+    pub strict: bool,
+
+    /// The name of the process manifest profile in use, if any.
+    pub profile: Option<String>,
+
+    /// The secrets of the process, masked in every log and report.
+    pub secrets: PupSecrets,
 }
 
 impl PupContext {
     /// Create a new context with a reference to the tasks
-    /// root folder, the workers root folder and the config file.
-    pub fn new(tasks: &Path, workers: &Path, root: &Path) -> Result<PupContext, PupError> {
+    /// root folders, the workers root folders and the config file.
+    pub fn new(tasks: &[PathBuf], workers: &[PathBuf], root: &Path) -> Result<PupContext, PupError> {
         return Ok(PupContext {
             env: HashMap::new(),
             global_env: build_global_env(root),
-            tasks: canonicalize(PathBuf::from(tasks)).map_err(|_e| {
-                PupError::with_message(
-                    PupErrorType::MissingTasksFolder,
-                    &format!("missing mandatory folder: {:?}", tasks),
-                )
-            })?,
-            workers: canonicalize(PathBuf::from(workers)).map_err(|_e| {
-                PupError::with_message(
-                    PupErrorType::MissingWorkerFolder,
-                    &format!("missing mandatory folder: {:?}", workers),
-                )
-            })?,
+            tasks: canonical_folders(tasks, PupErrorType::MissingTasksFolder)?,
+            workers: canonical_folders(workers, PupErrorType::MissingWorkerFolder)?,
+            workers_from_path: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+            strict: false,
+            profile: None,
+            secrets: PupSecrets::new(),
         });
     }
 
@@ -60,29 +75,20 @@ impl PupContext {
     }
 
     /// Load a context by 'name string' in the format foo.bar.foobar#version
+    /// The version may be a semver requirement, eg. foo.bar.foobar#^1.2, and the highest matching
+    /// version is used. If no version is given, the highest release version is used.
     pub fn load_task(&self, name: &str) -> Result<(PupTask, PupManifestVersion), PupError> {
         // Extract version & ident from name
-        let mut ident = String::from(name);
-        let mut version_ident: Option<String> = None;
-        if name.contains("#") {
-            let parts = name.split("#").collect::<Vec<&str>>();
-            ident = String::from(parts[0]);
-            version_ident = Some(String::from(parts[1]));
-        }
-
-        // Convert ident into path
-        let path = ident.replace(".", "/");
+        let (ident, version_ident) = split_task_name(name);
 
         // Try to load
-        let task = PupTask::new(self.clone(), &ident, path)?;
+        let task = PupTask::new(self.clone(), &ident, ident.replace(".", "/"))?;
 
         // Check the required version exists
         let version: PupManifestVersion = match version_ident {
-            Some(version_id) => {
-                let matched = task.manifest.versions.iter().find(|v| {
-                    return v.version == version_id;
-                });
-                if matched.is_none() {
+            Some(version_id) => match task.manifest.find_version(&version_id) {
+                Some(v) => v.clone(),
+                None => {
                     return Err(PupError::with_message(
                         PupErrorType::MissingVersion,
                         &format!(
@@ -92,68 +98,166 @@ impl PupContext {
                         ),
                     ));
                 }
-                matched.unwrap().clone()
-            }
-            None => {
-                if !task.manifest.versions.len() == 0 {
+            },
+            None => match task.manifest.default_version() {
+                Some(v) => v.clone(),
+                None => {
                     return Err(PupError::with_message(
                         PupErrorType::MissingVersion,
                         &format!("No versions available on {}", path::display(task.path)),
                     ));
                 }
-                task.manifest.versions[task.manifest.versions.len() - 1].clone()
-            }
+            },
         };
 
         return Ok((task, version));
     }
 
-    /// Find a worker by the name 'name' in the workers folder, and return a PupWorker for it.
+    /// Load a task by 'name string', using exactly the given version id instead of
+    /// whatever version the name asks for; eg. the version recorded in a lockfile.
+    pub fn load_locked_task(
+        &self,
+        name: &str,
+        version_id: &str,
+    ) -> Result<(PupTask, PupManifestVersion), PupError> {
+        let (ident, _) = split_task_name(name);
+        let task = PupTask::new(self.clone(), &ident, ident.replace(".", "/"))?;
+        let version = match task.manifest.versions.iter().find(|v| v.version == version_id) {
+            Some(v) => v.clone(),
+            None => {
+                return Err(PupError::with_message(
+                    PupErrorType::MissingVersion,
+                    &format!(
+                        "Locked version '{}' of {} no longer exists on {}",
+                        version_id,
+                        name,
+                        path::display(task.path)
+                    ),
+                ));
+            }
+        };
+        return Ok((task, version));
+    }
+
+    /// Return the first tasks root that contains the folder for a task, eg. tests/actions/setVersion.
+    /// If no root does, the first root is returned, so the error names a sensible path.
+    pub fn task_root<P: AsRef<Path>>(&self, task_folder: P) -> PathBuf {
+        let mut logger = get_logger();
+        for root in self.tasks.iter() {
+            if exists(join(root, task_folder.as_ref())) {
+                logger.log(
+                    Level::Debug,
+                    format!("Found task {} in {}", task_folder.as_ref().display(), path::display(root)),
+                );
+                return root.clone();
+            }
+        }
+        return self.tasks.first().cloned().unwrap_or(PathBuf::from("."));
+    }
+
+    /// Find a worker by the name 'name' in the workers folders, or on the PATH if
+    /// workers_from_path is set, and return a PupWorker for it.
     pub fn load_worker(&self, name: &str) -> Result<PupWorker, PupError> {
         let mut logger = get_logger();
 
-        let attempt1 = join(&self.workers, name);
-        logger.log(
-            Level::Debug,
-            format!("Checking for: {}", path::display(&attempt1)),
-        );
-        if exists(&attempt1) {
-            logger.log(Level::Debug, format!("Found: {}", path::display(&attempt1)));
-            return Ok(PupWorker {
-                path: attempt1,
-                name: String::from(name),
-                env: self.env.clone(),
-            });
+        let mut folders = self.workers.clone();
+        if self.workers_from_path {
+            if let Some(search_path) = env::var_os("PATH") {
+                folders.extend(env::split_paths(&search_path));
+            }
         }
 
-        let attempt2 = join(&self.workers, format!("{}.exe", name));
-        logger.log(
-            Level::Debug,
-            format!("Checking for: {}", path::display(&attempt2)),
-        );
-        if exists(&attempt2) {
-            logger.log(Level::Debug, format!("Found: {}", path::display(&attempt2)));
-            return Ok(PupWorker {
-                path: attempt2,
-                name: String::from(name),
-                env: self.env.clone(),
-            });
+        for folder in folders.iter() {
+            for file_name in [String::from(name), format!("{}.exe", name)].iter() {
+                let attempt = join(folder, file_name);
+                logger.log(
+                    Level::Debug,
+                    format!("Checking for: {}", path::display(&attempt)),
+                );
+                if exists(&attempt) {
+                    logger.log(
+                        Level::Debug,
+                        format!("Found worker {} in {}", name, path::display(folder)),
+                    );
+                    return Ok(PupWorker {
+                        path: attempt,
+                        name: String::from(name),
+                        env: self.env.clone(),
+                    });
+                }
+            }
         }
 
+        let searched: Vec<String> = self.workers.iter().map(|w| path::display(w)).collect();
         return Err(PupError::with_message(
             PupErrorType::MissingWorker,
             &format!(
-                "Unable to find any worker '{}' in {}",
+                "Unable to find any worker '{}' in {}{}",
                 name,
-                path::display(&self.workers)
+                searched.join(", "),
+                if self.workers_from_path { " or on the PATH" } else { "" }
             ),
         ));
     }
 }
 
+/// Resolve every root folder, failing if any of them is missing
+fn canonical_folders(folders: &[PathBuf], error_type: PupErrorType) -> Result<Vec<PathBuf>, PupError> {
+    let mut rtn = Vec::new();
+    for folder in folders.iter() {
+        rtn.push(canonicalize(folder).map_err(|_e| {
+            PupError::with_message(error_type, &format!("missing mandatory folder: {:?}", folder))
+        })?);
+    }
+    return Ok(rtn);
+}
+
+/// Split a name in the format foo.bar.foobar#version into the ident and the version, if any
+fn split_task_name(name: &str) -> (String, Option<String>) {
+    let parts = name.splitn(2, "#").collect::<Vec<&str>>();
+    if parts.len() == 2 {
+        return (String::from(parts[0]), Some(String::from(parts[1])));
+    }
+    return (String::from(name), None);
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::testing::test_fixture;
+    use crate::testing::{test_context_folder, test_fixture};
+    use crate::utils::path::join;
+    use crate::PupProcess;
+
+    fn overlay_fixture() -> PupProcess {
+        return PupProcess::load_from(join(test_context_folder(), "overlay.yml"), None).unwrap();
+    }
+
+    #[test]
+    fn load_task_from_first_root_with_it() {
+        let process = overlay_fixture();
+        let (task, version) = process.context.load_task("tests.actions.setVersion").unwrap();
+        assert_eq!(version.version, "9.0.0");
+        assert!(task.path.starts_with(join(test_context_folder(), "overlay")));
+
+        let (task, version) = process.context.load_task("tests.builds.deployment").unwrap();
+        assert_eq!(version.version, "0.0.2");
+        assert!(task.path.starts_with(join(test_context_folder(), "tasks")));
+    }
+
+    #[test]
+    fn load_worker_from_every_root() {
+        let mut process = overlay_fixture();
+        let overlay = process.context.load_worker("pup-overlay").unwrap();
+        assert!(overlay.path.starts_with(join(test_context_folder(), "overlay")));
+        let tasks = process.context.load_worker("pup-tasks").unwrap();
+        assert!(tasks.path.starts_with(join(test_context_folder(), "bin")));
+
+        let err = process.context.load_worker("sh").err().unwrap();
+        assert!(err.error_detail.contains("overlay"));
+        if cfg!(unix) {
+            process.context.workers_from_path = true;
+            assert!(process.context.load_worker("sh").is_ok());
+        }
+    }
 
     #[test]
     fn load_simple_task() {
@@ -166,6 +270,53 @@ mod tests {
         assert_eq!(version.version, "0.0.2");
     }
 
+    #[test]
+    fn load_default_version_is_highest_release() {
+        let process = test_fixture();
+        let (_, version) = process.context.load_task("tests.versions.unordered").unwrap();
+        assert_eq!(version.version, "1.10.0");
+
+        let (_, version) = process.context.load_task("tests.builds.deployment").unwrap();
+        assert_eq!(version.version, "0.0.2");
+    }
+
+    #[test]
+    fn load_version_from_requirement() {
+        let process = test_fixture();
+        let expected = vec![
+            ("tests.versions.unordered#^1.2", "1.10.0"),
+            ("tests.versions.unordered#>=1.0, <1.5", "1.2.0"),
+            ("tests.versions.unordered#~0.9", "0.9.0"),
+            ("tests.versions.unordered#>=2.0.0-beta.1", "2.0.0-beta.1"),
+            ("tests.actions.setVersion#~0.0.2", "0.0.2"),
+            ("tests.builds.deployment#0.0.2-skip", "0.0.2-skip"),
+        ];
+        for (name, version_id) in expected {
+            let (_, version) = process.context.load_task(name).unwrap();
+            assert_eq!(version.version, version_id);
+        }
+    }
+
+    #[test]
+    fn fails_to_load_unmatched_requirement() {
+        let process = test_fixture();
+        let task = process.context.load_task("tests.versions.unordered#^3");
+        assert!(task.is_err());
+    }
+
+    #[test]
+    fn sorts_versions_semantically() {
+        let process = test_fixture();
+        let (task, _) = process.context.load_task("tests.versions.unordered").unwrap();
+        let sorted: Vec<&str> = task
+            .manifest
+            .sorted_versions()
+            .iter()
+            .map(|v| v.version.as_str())
+            .collect();
+        assert_eq!(sorted, vec!["0.9.0", "1.2.0", "1.10.0", "2.0.0-beta.1"]);
+    }
+
     #[test]
     fn fails_to_load_missing_version() {
         let process = test_fixture();
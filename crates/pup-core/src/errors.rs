@@ -1,10 +1,12 @@
+use crate::utils::path;
 use handlebars;
 use serde_yaml;
 use std::error::Error;
 use std::fmt;
 use std::io;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum PupErrorType {
     InnerError,
     MissingVersion,
@@ -20,6 +22,22 @@ pub enum PupErrorType {
     InvalidRequest,
     MissingArgument,
     FailedToSpawnWorker,
+    RecursiveTask,
+    MaxDepthExceeded,
+    InvalidLockfile,
+    LockfileMismatch,
+    InvalidRunState,
+    RunStateMismatch,
+    InvalidGlobPattern,
+    InvalidDuration,
+    WorkerTimedOut,
+    StepsFailed,
+    UndefinedVariable,
+    InvalidEnvFile,
+    InvalidProfile,
+    RecursiveManifest,
+    ValidationFailed,
+    InvalidYaml,
 }
 
 #[derive(Debug)]
@@ -32,6 +50,46 @@ pub struct PupError {
 
     /// The inner error if any
     pub error_inner: Option<Box<Error + Send + 'static>>,
+
+    /// The log of the worker that failed, if any
+    pub log_path: Option<PathBuf>,
+
+    /// Where in a source file the error is, if it came from parsing one
+    pub location: Option<PupSourceLocation>,
+}
+
+/// A position in a source file, eg. a misspelled key in a manifest
+#[derive(Debug, Clone, PartialEq)]
+pub struct PupSourceLocation {
+    /// The file the error is in
+    pub path: PathBuf,
+
+    /// The line and column of the error, both from 1
+    pub line: usize,
+    pub column: usize,
+
+    /// The offending line, with a marker under the column
+    pub snippet: String,
+}
+
+impl PupSourceLocation {
+    pub fn new(source_path: &Path, raw: &str, line: usize, column: usize) -> PupSourceLocation {
+        let text = raw.lines().nth(line.saturating_sub(1)).unwrap_or("");
+        let gutter = format!("{}", line).len();
+        let snippet = format!(
+            "{} | {}\n{} | {}^",
+            line,
+            text,
+            " ".repeat(gutter),
+            " ".repeat(column.saturating_sub(1))
+        );
+        return PupSourceLocation {
+            path: PathBuf::from(source_path),
+            line,
+            column,
+            snippet,
+        };
+    }
 }
 
 impl PupError {
@@ -40,6 +98,8 @@ impl PupError {
             error_type,
             error_detail: format!("{:?}: {}", error_type, error_detail),
             error_inner: None,
+            log_path: None,
+            location: None,
         };
     }
 
@@ -52,6 +112,53 @@ impl PupError {
             error_type,
             error_detail: format!("{:?}: {}", error_type, error_detail),
             error_inner: Some(Box::new(inner_error) as Box<Error + Send + 'static>),
+            log_path: None,
+            location: None,
+        };
+    }
+
+    /// An error parsing yaml from 'source_path'; the detail names the file, line and column,
+    /// followed by a snippet of the offending line.
+    pub fn with_yaml_error(source_path: &Path, raw: &str, err: serde_yaml::Error) -> Self {
+        let location = err
+            .location()
+            .map(|l| PupSourceLocation::new(source_path, raw, l.line(), l.column()));
+        let detail = match location {
+            Some(ref l) => format!("{}:{}:{}: {}\n{}", path::display(source_path), l.line, l.column, err, l.snippet),
+            None => format!("{}: {}", path::display(source_path), err),
+        };
+        let mut rtn = PupError::with_error(PupErrorType::InvalidYaml, &detail, err);
+        rtn.location = location;
+        return rtn;
+    }
+
+    /// Prefix the detail of this error with where it happened, eg. the step it came from.
+    pub fn with_context(mut self, context: &str) -> Self {
+        let prefix = format!("{:?}: ", self.error_type);
+        let detail = match self.error_detail.starts_with(&prefix) {
+            true => self.error_detail[prefix.len()..].to_string(),
+            false => self.error_detail.clone(),
+        };
+        self.error_detail = format!("{}{}: {}", prefix, context, detail);
+        return self;
+    }
+
+    /// Point this error at the log of the worker that failed, and include the end of it.
+    pub fn with_log(mut self, log_path: &Path, tail: Vec<String>) -> Self {
+        self.error_detail = format!("{}\nLog: {}", self.error_detail, log_path.display());
+        for line in tail.iter() {
+            self.error_detail = format!("{}\n    {}", self.error_detail, line);
+        }
+        self.log_path = Some(PathBuf::from(log_path));
+        return self;
+    }
+
+    /// The first line of the error detail, with the log path if there is one
+    pub fn summary(&self) -> String {
+        let first = self.error_detail.lines().next().unwrap_or("");
+        return match self.log_path {
+            Some(ref p) => format!("{} (log: {})", first, p.display()),
+            None => first.to_string(),
         };
     }
 }
@@ -62,6 +169,8 @@ impl From<PupErrorType> for PupError {
             error_type,
             error_detail: format!("{:?}", error_type),
             error_inner: None,
+            log_path: None,
+            location: None,
         };
     }
 }
@@ -84,12 +193,20 @@ impl From<serde_yaml::Error> for PupError {
     }
 }
 
+impl From<serde_json::Error> for PupError {
+    fn from(err: serde_json::Error) -> Self {
+        return PupError::from(Box::new(err) as Box<Error + Send + 'static>);
+    }
+}
+
 impl From<Box<Error + Send + 'static>> for PupError {
     fn from(err: Box<Error + Send + 'static>) -> PupError {
         return PupError {
             error_type: PupErrorType::InnerError,
             error_detail: String::from(err.description()),
             error_inner: Some(err),
+            log_path: None,
+            location: None,
         };
     }
 }
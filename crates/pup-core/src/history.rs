@@ -0,0 +1,268 @@
+use crate::errors::{PupError, PupErrorType};
+use crate::runner::{PupAction, PupStepRole};
+use crate::utils::path;
+use crate::utils::secrets;
+use serde_json;
+use std::collections::BTreeSet;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use time::Tm;
+
+/// The name of the run history, in the process state folder
+pub const HISTORY_NAME: &str = "history.jsonl";
+
+/// The history of every run, stored as one json record per line
+pub struct PupHistory {
+    path: PathBuf,
+}
+
+/// The record of a single finished run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PupHistoryRecord {
+    /// The unique id of the run
+    pub run_id: String,
+
+    /// The root tasks that were run
+    pub tasks: Vec<String>,
+
+    /// Every task#version used in the run
+    pub versions: Vec<String>,
+
+    /// When the run started and finished, as RFC 3339 timestamps
+    pub started: String,
+    pub finished: String,
+
+    /// How long the run took
+    pub duration_ms: i64,
+
+    /// Did every step succeed?
+    pub success: bool,
+
+    /// The first step that failed, if any
+    pub failed_step: Option<String>,
+
+    /// Every step in the plan, in order
+    pub steps: Vec<PupHistoryStep>,
+}
+
+/// The outcome of a single step in a finished run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PupHistoryStep {
+    /// The task#version of the step
+    pub step: String,
+
+    /// The depth of the step in the plan, from 1 for the root tasks
+    pub depth: usize,
+
+    /// One of passed, failed, skipped or not_run
+    pub outcome: PupStepOutcome,
+
+    /// How long the step took, if it ran
+    pub duration_ms: Option<i64>,
+
+    /// Why the step failed or was skipped, if it did
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PupStepOutcome {
+    Passed,
+    Failed,
+    Skipped,
+    NotRun,
+}
+
+impl PupHistory {
+    pub fn new(history_path: &Path) -> PupHistory {
+        return PupHistory {
+            path: PathBuf::from(history_path),
+        };
+    }
+
+    /// Add a run to the end of the history, creating it if required
+    pub fn append(&self, record: &PupHistoryRecord) -> Result<(), PupError> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Mask each field before it is serialized, as escaping a secret would hide it
+        let mut value = serde_json::to_value(record)?;
+        secrets::active().mask_json(&mut value);
+        let raw = serde_json::to_string(&value)?;
+        let mut fp = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        fp.write_all(raw.as_bytes())?;
+        fp.write_all(b"\n")?;
+        return Ok(());
+    }
+
+    /// Return every run in the history, oldest first
+    pub fn runs(&self) -> Result<Vec<PupHistoryRecord>, PupError> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let mut raw = String::new();
+        File::open(&self.path)?.read_to_string(&mut raw)?;
+        let mut rtn = Vec::new();
+        for (index, line) in raw.lines().enumerate() {
+            if line.trim().len() == 0 {
+                continue;
+            }
+            match serde_json::from_str(line) {
+                Ok(record) => rtn.push(record),
+                Err(err) => {
+                    return Err(PupError::with_error(
+                        PupErrorType::InvalidRunState,
+                        &format!(
+                            "Unable to read run history: {}: line {}: {}",
+                            path::display(&self.path),
+                            index + 1,
+                            err
+                        ),
+                        err,
+                    ));
+                }
+            }
+        }
+        return Ok(rtn);
+    }
+
+    /// Return the run with the given id
+    pub fn find(&self, run_id: &str) -> Result<PupHistoryRecord, PupError> {
+        return match self.runs()?.into_iter().find(|r| r.run_id == run_id) {
+            Some(record) => Ok(record),
+            None => Err(PupError::with_message(
+                PupErrorType::InvalidRunState,
+                &format!("No run with id {} in {}", run_id, path::display(&self.path)),
+            )),
+        };
+    }
+}
+
+impl PupHistoryRecord {
+    /// Create a record of a run of the given root tasks
+    pub(crate) fn from_run(
+        run_id: &str,
+        names: &[String],
+        roots: &[PupAction],
+        started: Tm,
+        finished: Tm,
+    ) -> PupHistoryRecord {
+        let mut steps = Vec::new();
+        let mut versions = BTreeSet::new();
+        for root in roots.iter() {
+            collect_steps(root, 1, &mut steps, &mut versions);
+        }
+        let failed_step = steps
+            .iter()
+            .find(|s| s.outcome == PupStepOutcome::Failed && s.reason.is_some())
+            .map(|s| s.step.clone());
+        return PupHistoryRecord {
+            run_id: run_id.to_string(),
+            tasks: names.to_vec(),
+            versions: versions.into_iter().collect(),
+            started: format!("{}", started.rfc3339()),
+            finished: format!("{}", finished.rfc3339()),
+            duration_ms: (finished - started).num_milliseconds(),
+            success: roots.iter().all(|r| r.success),
+            failed_step,
+            steps,
+        };
+    }
+}
+
+fn collect_steps(
+    action: &PupAction,
+    depth: usize,
+    steps: &mut Vec<PupHistoryStep>,
+    versions: &mut BTreeSet<String>,
+) {
+    // Undo steps are only part of the history if they were needed
+    if action.role == PupStepRole::Undo && !action.completed {
+        return;
+    }
+
+    let (outcome, reason) = if let Some(ref reason) = action.skip_reason {
+        (PupStepOutcome::Skipped, Some(reason.to_string()))
+    } else if !action.completed {
+        (PupStepOutcome::NotRun, None)
+    } else if !action.success {
+        (PupStepOutcome::Failed, action.error.clone())
+    } else {
+        (PupStepOutcome::Passed, None)
+    };
+    versions.insert(action.name());
+    steps.push(PupHistoryStep {
+        step: action.name(),
+        depth,
+        outcome,
+        duration_ms: action.duration.map(|d| d.as_millis() as i64),
+        reason,
+    });
+
+    // Skipped undo steps are only part of the history if the undo steps ran
+    let undo_skipped = match action.undone {
+        true => action.undo_skipped.as_slice(),
+        false => &[],
+    };
+    for skipped in action.skipped.iter().chain(undo_skipped.iter()) {
+        steps.push(PupHistoryStep {
+            step: skipped.step.clone(),
+            depth: depth + 1,
+            outcome: PupStepOutcome::Skipped,
+            duration_ms: None,
+            reason: Some(format!("{} token was: {}", skipped.marker, skipped.token)),
+        });
+    }
+    for child in action.all_children() {
+        collect_steps(child, depth + 1, steps, versions);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PupHistory, PupHistoryRecord, PupHistoryStep, PupStepOutcome};
+    use crate::errors::PupErrorType;
+    use crate::testing::test_output_path;
+    use std::fs;
+
+    fn record(run_id: &str, success: bool) -> PupHistoryRecord {
+        return PupHistoryRecord {
+            run_id: run_id.to_string(),
+            tasks: vec![String::from("tests.builds.deployment")],
+            versions: vec![String::from("tests.builds.deployment#0.0.2")],
+            started: String::from("2019-01-01T10:00:00+00:00"),
+            finished: String::from("2019-01-01T10:01:30+00:00"),
+            duration_ms: 90000,
+            success,
+            failed_step: None,
+            steps: vec![PupHistoryStep {
+                step: String::from("tests.builds.deployment#0.0.2"),
+                depth: 1,
+                outcome: PupStepOutcome::Passed,
+                duration_ms: Some(90000),
+                reason: None,
+            }],
+        };
+    }
+
+    #[test]
+    fn test_append_and_find_runs() {
+        let history_path = test_output_path("history.jsonl");
+        let _ = fs::remove_file(&history_path);
+        let history = PupHistory::new(&history_path);
+        assert_eq!(history.runs().unwrap().len(), 0);
+
+        history.append(&record("first", false)).unwrap();
+        history.append(&record("second", true)).unwrap();
+        let runs = history.runs().unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0].run_id, "first");
+        assert!(history.find("second").unwrap().success);
+        assert_eq!(
+            history.find("third").err().unwrap().error_type,
+            PupErrorType::InvalidRunState
+        );
+    }
+}
@@ -6,9 +6,17 @@ extern crate lazy_static;
 
 extern crate dunce;
 extern crate serde_yaml;
+extern crate serde_json;
 extern crate base_logging;
 extern crate time;
+#[macro_use]
 extern crate handlebars;
+extern crate sha1;
+extern crate semver;
+extern crate glob;
+extern crate yaml_rust;
+#[cfg(unix)]
+extern crate libc;
 
 mod context;
 mod manifest;
@@ -17,15 +25,21 @@ mod errors;
 mod runner;
 mod worker;
 mod process;
+mod lock;
+mod history;
+mod validate;
 
 pub mod utils;
 pub mod logger;
 pub mod testing;
 
 pub use context::PupContext;
-pub use runner::PupActionOptions;
-pub use errors::{PupError, PupErrorType};
+pub use runner::{forward_signals_to_workers, PupActionOptions, PupEvent, PupEventSink};
+pub use errors::{PupError, PupErrorType, PupSourceLocation};
 pub use process::PupProcess;
+pub use lock::{PupLockMode, PupLockfile};
+pub use history::{PupHistory, PupHistoryRecord, PupHistoryStep, PupStepOutcome};
+pub use validate::{PupProblem, PupValidation};
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,147 @@
+use crate::errors::{PupError, PupErrorType};
+use crate::utils::hash::hash_folder;
+use crate::utils::path;
+use serde_yaml;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The name of the lockfile, which lives next to the process manifest
+pub const LOCKFILE_NAME: &str = "pup.lock";
+
+/// How a process uses its lockfile when resolving the versions of steps
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PupLockMode {
+    /// Use the locked version of a step if there is one, otherwise resolve it as usual.
+    Honour,
+
+    /// Resolve versions as usual, and fail if anything resolves differently to the lockfile.
+    Locked,
+
+    /// Ignore the lockfile entirely.
+    Ignore,
+}
+
+/// The resolved version of every step in every root task
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PupLockfile {
+    /// The locked steps for each root task, by root task name
+    #[serde(default)]
+    pub tasks: BTreeMap<String, PupLockRoot>,
+}
+
+/// The resolved version of every step in a single root task
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PupLockRoot {
+    /// The version each step reference resolved to, by reference, eg. foo.bar#^1.2
+    #[serde(default)]
+    pub steps: BTreeMap<String, PupLockEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PupLockEntry {
+    /// The exact version id of the task that was used
+    pub version: String,
+
+    /// The content hash of the task folder
+    #[serde(default)]
+    pub hash: String,
+
+    /// The path to the task folder
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+impl PupLockfile {
+    pub fn try_from(lock_path: &Path) -> Result<Self, PupError> {
+        return Self::read_lockfile(lock_path).map_err(|err| {
+            return PupError::with_error(
+                PupErrorType::InvalidLockfile,
+                &format!(
+                    "Unable to read lockfile: {}: {:?}",
+                    path::display(lock_path),
+                    err.description()
+                ),
+                err,
+            );
+        });
+    }
+
+    fn read_lockfile(lock_path: &Path) -> Result<Self, PupError> {
+        let mut fp = File::open(&lock_path)?;
+        let mut raw = String::new();
+        fp.read_to_string(&mut raw)?;
+        return Ok(serde_yaml::from_str(&raw)?);
+    }
+
+    /// Write the lockfile to disk
+    pub fn save(&self, lock_path: &Path) -> Result<(), PupError> {
+        let raw = serde_yaml::to_string(self)?;
+        let mut fp = File::create(lock_path)?;
+        fp.write_all(raw.as_bytes())?;
+        fp.write_all(b"\n")?;
+        return Ok(());
+    }
+}
+
+impl PupLockRoot {
+    /// Record the version a step reference resolved to
+    pub fn insert(&mut self, reference: &str, version: &str, path: &Path) {
+        self.steps.insert(
+            reference.to_string(),
+            PupLockEntry {
+                version: version.to_string(),
+                hash: String::new(),
+                path: PathBuf::from(path),
+            },
+        );
+    }
+
+    /// Return the locked version for a step reference, if any
+    pub fn version(&self, reference: &str) -> Option<&str> {
+        return self.steps.get(reference).map(|e| e.version.as_str());
+    }
+
+    /// Compute the content hash of every task folder
+    pub fn hash_folders(&mut self) -> Result<(), PupError> {
+        let mut hashes: BTreeMap<PathBuf, String> = BTreeMap::new();
+        for entry in self.steps.values_mut() {
+            if !hashes.contains_key(&entry.path) {
+                hashes.insert(entry.path.clone(), hash_folder(&entry.path)?);
+            }
+            entry.hash = hashes[&entry.path].clone();
+        }
+        return Ok(());
+    }
+
+    /// Describe every way a freshly resolved root differs from this locked root
+    pub fn differences(&self, resolved: &PupLockRoot) -> Vec<String> {
+        let mut rtn = Vec::new();
+        for (reference, entry) in resolved.steps.iter() {
+            match self.steps.get(reference) {
+                Some(locked) => {
+                    if locked.version != entry.version {
+                        rtn.push(format!(
+                            "{} resolves to {}, but is locked to {}",
+                            reference, entry.version, locked.version
+                        ));
+                    } else if locked.hash != entry.hash {
+                        rtn.push(format!(
+                            "{} #{} has changed since it was locked",
+                            reference, entry.version
+                        ));
+                    }
+                }
+                None => rtn.push(format!("{} is not in the lockfile", reference)),
+            }
+        }
+        for reference in self.steps.keys() {
+            if !resolved.steps.contains_key(reference) {
+                rtn.push(format!("{} is no longer used", reference));
+            }
+        }
+        return rtn;
+    }
+}
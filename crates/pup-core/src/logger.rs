@@ -2,6 +2,7 @@ use ::base_logging::Logger;
 use ::base_logging::Level;
 use ::base_logging::LogFormatter;
 use ::base_logging::loggers::ConsoleLogger;
+use crate::utils::secrets;
 use std::collections::HashMap;
 use ::time::Tm;
 use ::time;
@@ -15,6 +16,13 @@ lazy_static! {
 
 impl LogFormatter for PupFormatter {
     fn log_format(&self, level: Level, timestamp: Tm, message: Option<&str>, properties: Option<HashMap<&str, &str>>) -> String {
+        return secrets::mask(&self.format_raw(level, timestamp, message, properties));
+    }
+}
+
+impl PupFormatter {
+    /// Format a message, before any secrets are masked
+    fn format_raw(&self, level: Level, timestamp: Tm, message: Option<&str>, properties: Option<HashMap<&str, &str>>) -> String {
         if level == Level::Debug {
             let timestring = match time::strftime("%b %d %H:%M:%S", &timestamp) {
                 Ok(i) => i,
@@ -28,9 +36,7 @@ impl LogFormatter for PupFormatter {
             };
         }
     }
-}
 
-impl PupFormatter {
     fn combine(&self, message: Option<&str>, properties: Option<HashMap<&str, &str>>) -> String {
         let mut rtn = String::new();
         match message {
@@ -3,6 +3,7 @@ use crate::utils::path;
 use crate::utils::path::{exists, join};
 use crate::{PupError, PupErrorType};
 use base_logging::Level;
+use semver::{Version, VersionReq};
 use serde_yaml;
 use std::collections::HashMap;
 use std::error::Error;
@@ -12,6 +13,7 @@ use std::path::Path;
 use std::path::PathBuf;
 
 #[derive(Debug, Serialize, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct PupManifest {
     /// The name of the action in the root/workers/ folder to execute with this action.
     /// The action "foo" maps to the executable "foo" or "foo.exe" as appropriate.
@@ -22,8 +24,10 @@ pub struct PupManifest {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct PupManifestVersion {
     /// The version identifier for this version, eg. 0.0.1
+    /// This should be a semantic version, so that steps can refer to it with a version requirement.
     /// The version config file is passed to the action, eg. root/tasks/foo/bar/z/config/0.0.1.json
     pub version: String,
 
@@ -33,15 +37,39 @@ pub struct PupManifestVersion {
     #[serde(default)]
     pub steps: Vec<PupManifestStep>,
 
+    /// Steps to run after the steps and the action for this version, whether they succeed or fail.
+    /// eg. to stop a service that one of the steps started.
+    #[serde(default)]
+    pub finally: Vec<PupManifestStep>,
+
+    /// The number of independent steps to run at the same time.
+    /// If this is zero, use the number of jobs the runner was invoked with.
+    #[serde(default)]
+    pub jobs: usize,
+
+    /// Glob patterns for the files this version reads, relative to the version folder.
+    /// Allow handlebar's templates here, eg. "{{SOURCE_PATH}}/**/*.rs"
+    /// If the inputs, env and task folder are unchanged since the worker last succeeded,
+    /// and all of the outputs exist, the worker is skipped.
+    #[serde(default)]
+    pub inputs: Vec<String>,
+
+    /// Glob patterns for the files this version writes, relative to the version folder.
+    /// Allow handlebar's templates here, eg. "{{BUILD_PATH}}/app.zip"
+    #[serde(default)]
+    pub outputs: Vec<String>,
+
     /// The path to the folder for this version
     #[serde(skip)]
     pub path: PathBuf,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct PupManifestStep {
     /// The verison identifier for the target step to run.
     /// eg. foo.bar.foo#0.0.1
+    /// The version may also be a semver requirement, eg. foo.bar.foo#^1.2 or foo.bar.foo#>=1.0, <2.0
     pub step: String,
 
     /// The set of extra env variables just for this step.
@@ -50,6 +78,13 @@ pub struct PupManifestStep {
     #[serde(default)]
     pub environment: HashMap<String, String>,
 
+    /// An env file for just this step; a .env file of KEY=VALUE lines or a .yml/.yaml map.
+    /// The path is relative to MANIFEST_HOME and may use templates, eg. "envs/{{TARGET}}.env".
+    /// Its values are layered over 'environment', which is layered over the parent env,
+    /// and are rendered from the parent env just as 'environment' values are.
+    #[serde(default)]
+    pub env_file: String,
+
     /// Allow this step to be skipped if some condition is met; if this value is any 'truish' string value skip the step.
     /// Allow handlebar's templates here, eg. skip: "{{SKIP_BUILD_STEP}}"
     /// Inherit the root env configuration as input variables.
@@ -63,12 +98,69 @@ pub struct PupManifestStep {
     #[serde(default)]
     #[serde(rename = "if")]
     pub if_marker: String,
+
+    /// Allow this step to run at the same time as its independent siblings,
+    /// if the version is configured to run more than one job at once.
+    #[serde(default)]
+    pub independent: bool,
+
+    /// The longest this step, including all of its own steps, may run for; eg. 30s, 10m or 1h30m.
+    /// If it runs for longer, the worker and every process it started are killed.
+    #[serde(default)]
+    pub timeout: String,
+
+    /// If set, retry the worker for this step when it fails.
+    #[serde(default)]
+    pub retry: Option<PupManifestRetry>,
+
+    /// If this step fails, log the failure and carry on as if it had succeeded.
+    #[serde(default)]
+    pub continue_on_failure: bool,
+
+    /// Steps that reverse this step. If this step succeeds but a later sibling, or the worker
+    /// of the task it is a step of, fails, the undo steps of every step that succeeded are run,
+    /// in reverse order.
+    /// The undo steps inherit the env of this step.
+    #[serde(default)]
+    pub undo: Vec<PupManifestStep>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PupManifestRetry {
+    /// The total number of times to try the worker, including the first.
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: usize,
+
+    /// How long to wait before retrying; eg. 5s or 1m
+    #[serde(default)]
+    pub delay: String,
+
+    /// Multiply the delay by this after each retry; eg. 2 doubles the delay every time.
+    #[serde(default = "default_retry_backoff")]
+    pub backoff: f64,
+
+    /// If set, only retry when the worker exits with one of these exit codes.
+    #[serde(default)]
+    pub exit_codes: Vec<i32>,
+}
+
+fn default_retry_attempts() -> usize {
+    3
+}
+
+fn default_retry_backoff() -> f64 {
+    1.0
 }
 
 impl PupManifest {
     pub fn try_from(task_folder: &Path) -> Result<Self, PupError> {
         let manifest_path = join(task_folder, "manifest.yml");
         return PupManifest::read_manifest(task_folder, &manifest_path).map_err(|err| {
+            // Yaml errors already say where they are
+            if err.error_type == PupErrorType::InvalidYaml {
+                return err;
+            }
             return PupError::with_error(
                 PupErrorType::MissingManifest,
                 &format!(
@@ -86,12 +178,74 @@ impl PupManifest {
         let mut raw = String::new();
         fp.read_to_string(&mut raw)?;
 
-        let mut manifest: PupManifest = serde_yaml::from_str(&raw)?;
+        let mut manifest: PupManifest = serde_yaml::from_str(&raw)
+            .map_err(|err| PupError::with_yaml_error(manifest_path, &raw, err))?;
         manifest.validate(&task_path)?;
 
         return Ok(manifest);
     }
 
+    /// Find the version matching a version identifier.
+    /// An exact match on the version id is used if there is one, otherwise the identifier is
+    /// treated as a semver requirement and the highest matching version is used.
+    pub fn find_version(&self, version_id: &str) -> Option<&PupManifestVersion> {
+        let exact = self.versions.iter().find(|v| v.version == version_id);
+        if exact.is_some() {
+            return exact;
+        }
+
+        let requirement = match VersionReq::parse(version_id) {
+            Ok(r) => r,
+            Err(_) => return None,
+        };
+        return self
+            .parsed_versions()
+            .into_iter()
+            .filter(|(parsed, _)| requirement.matches(parsed))
+            .max_by(|a, b| a.0.cmp(&b.0))
+            .map(|(_, v)| v);
+    }
+
+    /// Return the version to use when no version is requested.
+    /// This is the highest release version; if there are none, the highest pre-release version,
+    /// and if no versions are semantic versions, the last version in the manifest.
+    pub fn default_version(&self) -> Option<&PupManifestVersion> {
+        let parsed = self.parsed_versions();
+        let release = parsed
+            .iter()
+            .filter(|(v, _)| !v.is_prerelease())
+            .max_by(|a, b| a.0.cmp(&b.0));
+        if let Some((_, version)) = release {
+            return Some(version);
+        }
+        if let Some((_, version)) = parsed.iter().max_by(|a, b| a.0.cmp(&b.0)) {
+            return Some(version);
+        }
+        return self.versions.last();
+    }
+
+    /// Return all versions, lowest first.
+    /// Versions which are not semantic versions are listed first, in manifest order.
+    pub fn sorted_versions(&self) -> Vec<&PupManifestVersion> {
+        let mut rtn: Vec<&PupManifestVersion> = self
+            .versions
+            .iter()
+            .filter(|v| Version::parse(&v.version).is_err())
+            .collect();
+        let mut parsed = self.parsed_versions();
+        parsed.sort_by(|a, b| a.0.cmp(&b.0));
+        rtn.extend(parsed.into_iter().map(|(_, v)| v));
+        return rtn;
+    }
+
+    fn parsed_versions(&self) -> Vec<(Version, &PupManifestVersion)> {
+        return self
+            .versions
+            .iter()
+            .filter_map(|v| Version::parse(&v.version).ok().map(|parsed| (parsed, v)))
+            .collect();
+    }
+
     /// Check and load all paths in the manifest
     pub fn validate(&mut self, path: &Path) -> Result<(), PupError> {
         let mut logger = get_logger();
@@ -114,3 +268,24 @@ impl PupManifest {
         return Ok(());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::PupManifest;
+    use crate::errors::PupErrorType;
+    use crate::testing::test_context_folder;
+    use crate::utils::path::join;
+
+    #[test]
+    fn rejects_unknown_keys_with_location() {
+        let task_folder = join(test_context_folder(), "validate/tasks/broken/keys");
+        let err = PupManifest::try_from(&task_folder).err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::InvalidYaml);
+
+        let location = err.location.unwrap();
+        assert_eq!(location.path, join(&task_folder, "manifest.yml"));
+        assert_eq!((location.line, location.column), (4, 5));
+        assert_eq!(location.snippet, "4 |     stages: []\n  |     ^");
+        assert!(err.error_detail.contains("manifest.yml:4:5: versions[0]: unknown field `stages`"));
+    }
+}
@@ -1,12 +1,22 @@
 use crate::errors::{PupError, PupErrorType};
 use crate::utils::path::{absolute_path, join};
 
+use serde::{Deserialize, Deserializer};
 use serde_yaml;
-
-use crate::context::PupContext;
+use serde_yaml::Value;
+
+use crate::context::{PupContext, DEFAULT_MAX_DEPTH};
+use crate::lock::{PupLockMode, PupLockfile, LOCKFILE_NAME};
+use crate::utils::path::{display, exists};
+use crate::utils::env_file;
+use crate::utils::yaml::{field_mut, known_keys, remove_unknown_keys};
+use crate::logger::get_logger;
+use base_logging::Level;
 use crate::manifest::PupManifestVersion;
 use crate::runner::env::EnvHelper;
+use crate::history::{PupHistory, HISTORY_NAME};
 use crate::runner::PupRunner;
+use crate::runner::{PupRunRecord, FINGERPRINTS_NAME, RUN_RECORD_NAME};
 use crate::task::PupTask;
 use std::collections::HashMap;
 use std::error::Error;
@@ -15,6 +25,12 @@ use std::io::Read;
 use std::path::Path;
 use std::path::PathBuf;
 
+/// The name of the folder, next to the process manifest, that pup keeps run state in
+pub const STATE_FOLDER_NAME: &str = ".pup";
+
+/// The template variable that holds the name of the active profile, or '' if there is none
+pub const PROFILE_VARIABLE: &str = "PUP_PROFILE";
+
 #[derive(Debug)]
 pub struct PupProcess {
     /// The root path to the process sequence
@@ -28,26 +44,127 @@ pub struct PupProcess {
 
     /// The ambient external environment overrides, if any
     pub environment_overrides: HashMap<String, String>,
+
+    /// The path to the lockfile for this process
+    pub lockfile: PathBuf,
+
+    /// How to use the lockfile when resolving the versions of steps
+    pub lock_mode: PupLockMode,
+
+    /// The folder pup keeps the state of runs in
+    pub state_folder: PathBuf,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct PupProcessManifest {
+    /// A base manifest this one extends, relative to this file; eg. an org-wide base.yml.
+    /// Everything in this manifest is merged over the base:
+    /// tasks, secrets and env_files are added to the base ones, environment values and profiles
+    /// replace the base ones with the same key or name, strict is set if either sets it, and
+    /// workers_path, tasks_path and max_depth replace the base only if they are set, and
+    /// workers_from_path is set if either sets it.
+    /// Paths in each manifest stay relative to that manifest's own folder.
+    #[serde(default)]
+    pub extends: String,
+
+    /// Other manifests merged over the base, in order, before this one; with the same rules as 'extends'.
+    #[serde(default)]
+    pub include: Vec<String>,
+
     /// The set of root level tasks to expose
+    #[serde(default)]
     pub tasks: Vec<String>,
 
-    /// The folder to use for workers
-    pub workers_path: String,
+    /// The folder to use for workers, or a list of folders to search in order.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub workers_path: Vec<String>,
+
+    /// If a worker isn't in any workers_path folder, look for it on the PATH.
+    #[serde(default)]
+    pub workers_from_path: bool,
 
-    /// The tasks to use for tasks
-    pub tasks_path: String,
+    /// The folder to use for tasks, or a list of folders to search in order.
+    /// Each task comes from the first folder that has it, so a local folder listed before
+    /// a shared library checkout overrides just the tasks it contains.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub tasks_path: Vec<String>,
 
     /// The path to the environment to use for tasks
+    #[serde(default)]
     pub environment: HashMap<String, String>,
+
+    /// Named overlays of 'environment' and 'tasks', eg. staging or prod, selected with --profile.
+    #[serde(default)]
+    pub profiles: HashMap<String, PupProcessProfile>,
+
+    /// Env files layered over 'environment', in order, so later files win; eg. a shared
+    /// settings.env and then an ignored local.env of developer overrides.
+    /// The active profile is layered over them all, so --profile always picks its values.
+    /// Each is a .env file of KEY=VALUE lines or, if it ends in .yml or .yaml, a map.
+    /// Paths are relative to MANIFEST_HOME, and files that don't exist are skipped.
+    /// Values may use templates, rendered from MANIFEST_HOME, then the OS env, then any
+    /// overrides, exactly as 'environment' values are.
+    #[serde(default)]
+    pub env_files: Vec<String>,
+
+    /// The maximum nesting depth of steps; deeper task trees fail to load.
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+
+    /// Fail to build a plan if a step template uses an undefined variable.
+    #[serde(default)]
+    pub strict: bool,
+
+    /// Env keys, or glob patterns like *_TOKEN, whose values are masked in logs and reports,
+    /// along with any value rendered from them.
+    #[serde(default)]
+    pub secrets: Vec<String>,
+}
+
+/// A named overlay on the process manifest
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PupProcessProfile {
+    /// Another profile this one inherits the environment and tasks of
+    #[serde(default)]
+    pub inherits: String,
+
+    /// Env values layered over the inherited environment
+    #[serde(default)]
+    pub environment: HashMap<String, String>,
+
+    /// The root level tasks to expose in place of the inherited tasks, if any
+    #[serde(default)]
+    pub tasks: Vec<String>,
+}
+
+/// Accept either a single value or a list of values
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    return Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    });
 }
 
 impl PupProcessManifest {
     pub fn try_from(process_manifest_path: &Path) -> Result<Self, PupError> {
-        return Self::read_manifest(process_manifest_path).map_err(|err| {
+        return Self::read(process_manifest_path, false);
+    }
+
+    /// Read a single process manifest. If lenient, unknown keys are ignored instead of failing.
+    fn read(process_manifest_path: &Path, lenient: bool) -> Result<Self, PupError> {
+        return Self::read_manifest(process_manifest_path, lenient).map_err(|err| {
+            // Yaml errors already say where they are
+            if err.error_type == PupErrorType::InvalidYaml {
+                return err;
+            }
             return PupError::with_error(
                 PupErrorType::MissingProcessManifest,
                 &format!(
@@ -60,21 +177,194 @@ impl PupProcessManifest {
         });
     }
 
-    fn read_manifest(process_manifest_path: &Path) -> Result<Self, PupError> {
+    fn read_manifest(process_manifest_path: &Path, lenient: bool) -> Result<Self, PupError> {
         let mut fp = File::open(&process_manifest_path)?;
         let mut raw = String::new();
         fp.read_to_string(&mut raw)?;
 
-        let mut manifest: PupProcessManifest = serde_yaml::from_str(&raw)?;
-        manifest.validate()?;
+        if lenient {
+            let mut value: Value = serde_yaml::from_str(&raw)
+                .map_err(|err| PupError::with_yaml_error(process_manifest_path, &raw, err))?;
+            Self::remove_unknown_keys(&mut value);
+            return serde_yaml::from_value(value)
+                .map_err(|err| PupError::with_yaml_error(process_manifest_path, &raw, err));
+        }
+        let manifest: PupProcessManifest = serde_yaml::from_str(&raw)
+            .map_err(|err| PupError::with_yaml_error(process_manifest_path, &raw, err))?;
+        return Ok(manifest);
+    }
 
+    /// Remove every key that isn't read from a raw process manifest and its profiles
+    fn remove_unknown_keys(value: &mut Value) {
+        remove_unknown_keys(value, &known_keys::<PupProcessManifest>("{}"));
+        let profile_keys = known_keys::<PupProcessProfile>("{}");
+        if let Some(profiles) = field_mut(value, "profiles").and_then(|p| p.as_mapping_mut()) {
+            for (_, profile) in profiles.iter_mut() {
+                remove_unknown_keys(profile, &profile_keys);
+            }
+        }
+    }
+
+    /// Read a process manifest, and merge it over every manifest it extends or includes
+    pub fn load(process_manifest_path: &Path) -> Result<Self, PupError> {
+        return Self::load_manifests(process_manifest_path, false);
+    }
+
+    /// Like load, but ignore unknown keys in every manifest instead of failing,
+    /// so they can be reported along with any other problems.
+    pub fn load_lenient(process_manifest_path: &Path) -> Result<Self, PupError> {
+        return Self::load_manifests(process_manifest_path, true);
+    }
+
+    fn load_manifests(process_manifest_path: &Path, lenient: bool) -> Result<Self, PupError> {
+        let mut manifest = Self::load_with_chain(process_manifest_path, &mut Vec::new(), lenient)?;
+        manifest.validate()?;
         return Ok(manifest);
     }
 
+    /// Load a manifest, tracking the chain of files from the root so cycles fail.
+    fn load_with_chain(
+        process_manifest_path: &Path,
+        chain: &mut Vec<PathBuf>,
+        lenient: bool,
+    ) -> Result<Self, PupError> {
+        let full_path = absolute_path(process_manifest_path).map_err(|_| {
+            return PupError::with_message(
+                PupErrorType::MissingProcessManifest,
+                &format!("Unable to read process manifest: {:?}", process_manifest_path),
+            );
+        })?;
+        if let Some(offset) = chain.iter().position(|p| *p == full_path) {
+            let mut cycle: Vec<String> = chain[offset..].iter().map(|p| display(p)).collect();
+            cycle.push(display(&full_path));
+            return Err(PupError::with_message(
+                PupErrorType::RecursiveManifest,
+                &format!("Process manifests include each other: {}", cycle.join(" -> ")),
+            ));
+        }
+
+        let manifest = Self::read(&full_path, lenient)?;
+        let folder = full_path.parent().map(|p| PathBuf::from(p)).unwrap_or(PathBuf::from("."));
+
+        // The base, then each include in order
+        let mut parents = Vec::new();
+        if manifest.extends != "" {
+            parents.push(manifest.extends.clone());
+        }
+        parents.extend(manifest.include.iter().cloned());
+
+        chain.push(full_path.clone());
+        let mut base: Option<PupProcessManifest> = None;
+        for parent_path in parents.iter() {
+            let mut parent = Self::load_with_chain(&join(&folder, parent_path), chain, lenient)?;
+            parent.resolve_paths(&join(&folder, parent_path));
+            base = Some(match base {
+                Some(b) => parent.merged_over(b),
+                None => parent,
+            });
+        }
+        chain.pop();
+
+        return Ok(match base {
+            Some(b) => manifest.merged_over(b),
+            None => manifest,
+        });
+    }
+
+    /// Make the paths in a manifest absolute, so they stay relative to their own file once merged
+    fn resolve_paths(&mut self, process_manifest_path: &Path) {
+        let folder = match absolute_path(process_manifest_path) {
+            Ok(p) => p.parent().map(|f| PathBuf::from(f)).unwrap_or(PathBuf::from(".")),
+            Err(_) => return,
+        };
+        let resolve = |p: &String| join(&folder, p).display().to_string();
+        self.tasks_path = self.tasks_path.iter().map(resolve).collect();
+        self.workers_path = self.workers_path.iter().map(resolve).collect();
+        self.env_files = self.env_files.iter().map(resolve).collect();
+    }
+
+    /// Merge this manifest over a base manifest
+    fn merged_over(self, base: PupProcessManifest) -> PupProcessManifest {
+        let mut rtn = base;
+        for task in self.tasks.into_iter() {
+            if !rtn.tasks.contains(&task) {
+                rtn.tasks.push(task);
+            }
+        }
+        rtn.environment.extend(self.environment);
+        rtn.profiles.extend(self.profiles);
+        rtn.env_files.extend(self.env_files);
+        for secret in self.secrets.into_iter() {
+            if !rtn.secrets.contains(&secret) {
+                rtn.secrets.push(secret);
+            }
+        }
+        if self.tasks_path.len() > 0 {
+            rtn.tasks_path = self.tasks_path;
+        }
+        if self.workers_path.len() > 0 {
+            rtn.workers_path = self.workers_path;
+        }
+        if self.max_depth.is_some() {
+            rtn.max_depth = self.max_depth;
+        }
+        rtn.strict = rtn.strict || self.strict;
+        rtn.workers_from_path = rtn.workers_from_path || self.workers_from_path;
+        rtn.extends = self.extends;
+        rtn.include = self.include;
+        return rtn;
+    }
+
     /// Check process manifest
     pub fn validate(&mut self) -> Result<(), PupError> {
+        if self.tasks_path.len() == 0 || self.workers_path.len() == 0 {
+            return Err(PupError::with_message(
+                PupErrorType::MissingProcessManifest,
+                "The process manifest, or a manifest it extends, must set tasks_path and workers_path",
+            ));
+        }
         Ok(())
     }
+
+    /// Flatten a profile and every profile it inherits from into a single profile
+    pub fn resolve_profile(&self, name: &str) -> Result<PupProcessProfile, PupError> {
+        // Walk up to the base profile, failing on unknown or cyclic profiles
+        let mut chain: Vec<&str> = Vec::new();
+        let mut next = name;
+        while next != "" {
+            if chain.contains(&next) {
+                chain.push(next);
+                return Err(PupError::with_message(
+                    PupErrorType::InvalidProfile,
+                    &format!("Profiles inherit from each other: {}", chain.join(" -> ")),
+                ));
+            }
+            let profile = self.profiles.get(next).ok_or_else(|| {
+                let mut known: Vec<&String> = self.profiles.keys().collect();
+                known.sort();
+                return PupError::with_message(
+                    PupErrorType::InvalidProfile,
+                    &format!("No profile named '{}'; known profiles: {:?}", next, known),
+                );
+            })?;
+            chain.push(next);
+            next = &profile.inherits;
+        }
+
+        // Then layer each profile over the one it inherits from
+        let mut rtn = PupProcessProfile::default();
+        rtn.inherits = name.to_string();
+        for profile_name in chain.iter().rev() {
+            let profile = &self.profiles[*profile_name];
+            for (key, value) in profile.environment.iter() {
+                rtn.environment.insert(key.to_string(), value.to_string());
+            }
+            if profile.tasks.len() > 0 {
+                rtn.tasks = profile.tasks.clone();
+            }
+        }
+        return Ok(rtn);
+    }
 }
 
 impl PupProcess {
@@ -83,25 +373,72 @@ impl PupProcess {
         path: P,
         env: Option<HashMap<String, String>>,
     ) -> Result<PupProcess, PupError> {
-        // Load manifest
-        let manifest = PupProcessManifest::try_from(path.as_ref())?;
+        return PupProcess::load_with_profile(path, env, None);
+    }
+
+    /// Create a process from a path, using the named profile from the manifest, if any.
+    /// The profile environment is layered over the manifest environment and env files, its tasks replace
+    /// the manifest tasks, and its name is available to templates as PUP_PROFILE.
+    pub fn load_with_profile<P: AsRef<Path>>(
+        path: P,
+        env: Option<HashMap<String, String>>,
+        profile_name: Option<&str>,
+    ) -> Result<PupProcess, PupError> {
+        let manifest = PupProcessManifest::load(path.as_ref())?;
+        return PupProcess::from_manifest(path, manifest, env, profile_name);
+    }
+
+    /// Like load_with_profile, but ignore unknown keys in the process manifests instead of
+    /// failing, so 'pup validate' can report them along with every other problem.
+    pub fn load_lenient<P: AsRef<Path>>(
+        path: P,
+        env: Option<HashMap<String, String>>,
+        profile_name: Option<&str>,
+    ) -> Result<PupProcess, PupError> {
+        let manifest = PupProcessManifest::load_lenient(path.as_ref())?;
+        return PupProcess::from_manifest(path, manifest, env, profile_name);
+    }
+
+    fn from_manifest<P: AsRef<Path>>(
+        path: P,
+        mut manifest: PupProcessManifest,
+        env: Option<HashMap<String, String>>,
+        profile_name: Option<&str>,
+    ) -> Result<PupProcess, PupError> {
         let manifest_path: PathBuf = PupProcess::get_manifest_folder(path.as_ref())?;
+        let profile = match profile_name {
+            Some(name) => manifest.resolve_profile(name)?,
+            None => PupProcessProfile::default(),
+        };
+        if profile.tasks.len() > 0 {
+            manifest.tasks = profile.tasks.clone();
+        }
 
         // Create context
-        let mut context = PupContext::new(
-            &join(&manifest_path, &manifest.tasks_path),
-            &join(&manifest_path, &manifest.workers_path),
-            &manifest_path,
-        )?;
-
-        // Render any env variables in the manifest
-        PupProcess::render_context_env(&manifest, &mut context, env)?;
+        let tasks: Vec<PathBuf> = manifest.tasks_path.iter().map(|p| join(&manifest_path, p)).collect();
+        let workers: Vec<PathBuf> = manifest.workers_path.iter().map(|p| join(&manifest_path, p)).collect();
+        let mut context = PupContext::new(&tasks, &workers, &manifest_path)?;
+        context.workers_from_path = manifest.workers_from_path;
+        context.max_depth = manifest.max_depth.unwrap_or(DEFAULT_MAX_DEPTH);
+        context.strict = manifest.strict;
+        context.profile = profile_name.map(|p| p.to_string());
+        context
+            .global_env
+            .insert(String::from(PROFILE_VARIABLE), profile_name.unwrap_or("").to_string());
+
+        // Render any env variables in the manifest, masking secrets from now on
+        context.secrets.add_patterns(&manifest.secrets)?;
+        context.secrets.activate();
+        PupProcess::render_context_env(&manifest, &profile, &mut context, env)?;
 
         return Ok(PupProcess {
-            path: PathBuf::from(path.as_ref()),
+            path: absolute_path(path.as_ref())?,
             manifest,
             context,
             environment_overrides: HashMap::new(),
+            lockfile: join(&manifest_path, LOCKFILE_NAME),
+            lock_mode: PupLockMode::Honour,
+            state_folder: join(&manifest_path, STATE_FOLDER_NAME),
         });
     }
 
@@ -128,10 +465,11 @@ impl PupProcess {
 
     fn render_context_env(
         manifest: &PupProcessManifest,
+        profile: &PupProcessProfile,
         context: &mut PupContext,
         overrides: Option<HashMap<String, String>>,
     ) -> Result<(), PupError> {
-        let mut env_helper = EnvHelper::new(&context.global_env);
+        let mut env_helper = EnvHelper::new(&context.global_env).with_secrets(&context.secrets);
         let mut ambient_params = env_helper.ambient_state().clone();
 
         // Blat existing values if any override
@@ -143,9 +481,30 @@ impl PupProcess {
             }
             None => {}
         };
+        context.secrets.track_env(&ambient_params);
+
+        // Layer env files, then the profile, over the inline environment
+        let mut source = manifest.environment.clone();
+        for file in manifest.env_files.iter() {
+            let env_file_path = env_file::resolve(&context.global_env, file);
+            if !exists(&env_file_path) {
+                let mut logger = get_logger();
+                logger.log(
+                    Level::Debug,
+                    format!("Skipping missing env file: {}", display(&env_file_path)),
+                );
+                continue;
+            }
+            source.extend(env_file::read_env_file(&env_file_path)?);
+        }
+        source.extend(profile.environment.clone());
 
-        let env = env_helper
-            .render_existing_keys_from_parent_scope(&manifest.environment, &ambient_params)?;
+        let mut env = env_helper.render_existing_keys_from_parent_scope(&source, &ambient_params)?;
+
+        // Steps can use the active profile too, unless the manifest defines its own value
+        if !env.contains_key(PROFILE_VARIABLE) {
+            env.insert(String::from(PROFILE_VARIABLE), context.global_env[PROFILE_VARIABLE].to_string());
+        }
         context.set_root_environment(&env);
         Ok(())
     }
@@ -156,16 +515,106 @@ impl PupProcess {
 
     pub fn runner(&mut self, task: &str) -> Result<PupRunner, PupError> {
         let mut runner = PupRunner::new(&self.context);
-        let _ = runner.add(task)?;
+        match self.lock_mode {
+            PupLockMode::Ignore => {
+                let _ = runner.add(task)?;
+            }
+            PupLockMode::Honour => {
+                let locked = match self.read_lock()? {
+                    Some(lock) => lock.tasks.get(task).cloned(),
+                    None => None,
+                };
+                let _ = runner.add_locked(task, locked)?;
+            }
+            PupLockMode::Locked => {
+                let locked = match self.read_lock()? {
+                    Some(ref lock) if lock.tasks.contains_key(task) => lock.tasks[task].clone(),
+                    _ => {
+                        return Err(PupError::with_message(
+                            PupErrorType::LockfileMismatch,
+                            &format!("No locked versions for {} in {}", task, display(&self.lockfile)),
+                        ));
+                    }
+                };
+                let mut resolved = runner.add_locked(task, None)?;
+                resolved.hash_folders()?;
+                let differences = locked.differences(&resolved);
+                if differences.len() > 0 {
+                    return Err(PupError::with_message(
+                        PupErrorType::LockfileMismatch,
+                        &format!("{} does not match the lockfile: {}", task, differences.join("; ")),
+                    ));
+                }
+            }
+        }
         return Ok(runner);
     }
+
+    /// Resolve every root task and write the version every step used to the lockfile.
+    pub fn write_lock(&mut self) -> Result<PupLockfile, PupError> {
+        let mut lock = PupLockfile::default();
+        for task in self.manifest.tasks.iter() {
+            let mut runner = PupRunner::new(&self.context);
+            let mut resolved = runner.add_locked(task, None)?;
+            resolved.hash_folders()?;
+            lock.tasks.insert(task.to_string(), resolved);
+        }
+        lock.save(&self.lockfile)?;
+        return Ok(lock);
+    }
+
+    /// The path the record of the last run is saved to
+    pub fn run_record_path(&self) -> PathBuf {
+        return join(&self.state_folder, RUN_RECORD_NAME);
+    }
+
+    /// The folder the worker logs for a run are written to
+    pub fn run_folder(&self, run_id: &str) -> PathBuf {
+        return join(&join(&self.state_folder, "runs"), run_id);
+    }
+
+    /// The history of every run of this process
+    pub fn history(&self) -> PupHistory {
+        return PupHistory::new(&join(&self.state_folder, HISTORY_NAME));
+    }
+
+    /// The path to the fingerprints of every incremental step that succeeded
+    pub fn fingerprints_path(&self) -> PathBuf {
+        return join(&self.state_folder, FINGERPRINTS_NAME);
+    }
+
+    /// Read the record of the last run, to resume it
+    pub fn last_run(&self) -> Result<PupRunRecord, PupError> {
+        let record_path = self.run_record_path();
+        if !exists(&record_path) {
+            return Err(PupError::with_message(
+                PupErrorType::InvalidRunState,
+                &format!("No previous run to resume: {}", display(&record_path)),
+            ));
+        }
+        return PupRunRecord::try_from(&record_path);
+    }
+
+    fn read_lock(&self) -> Result<Option<PupLockfile>, PupError> {
+        if !exists(&self.lockfile) {
+            return Ok(None);
+        }
+        return Ok(Some(PupLockfile::try_from(&self.lockfile)?));
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::PupProcess;
-    use crate::testing::test_context_process_path;
+    use super::{PupProcess, PupProcessManifest};
+    use serde_yaml;
+    use crate::context::DEFAULT_MAX_DEPTH;
+    use crate::errors::PupErrorType;
+    use crate::lock::{PupLockMode, PupLockfile};
+    use crate::testing::{test_context_folder, test_context_process_path};
+    use crate::utils::path::join;
     use crate::testing::test_fixture;
+    use crate::testing::test_output_path;
+    use std::fs;
 
     #[test]
     fn test_load_from_folder() {
@@ -187,6 +636,99 @@ mod tests {
         let _ = process.context;
     }
 
+    #[test]
+    fn test_env_files_are_layered_over_environment() {
+        let sample_process = test_context_process_path();
+        let process = PupProcess::load_from(sample_process, None).unwrap();
+        assert_eq!(process.context.env["layered"], "from-file");
+        let expected = format!("{}-shared", &process.context.global_env["MANIFEST_HOME"]);
+        assert_eq!(process.context.env["from_env_file"], expected);
+        assert_eq!(process.context.env["foo"], "bar");
+    }
+
+    #[test]
+    fn test_load_with_profile() {
+        let sample_process = test_context_process_path();
+        let mut process = PupProcess::load_with_profile(sample_process, None, Some("prod")).unwrap();
+        assert_eq!(process.manifest.tasks, vec![String::from("tests.builds.deployment")]);
+        assert_eq!(process.context.env["foo"], "staging-foo");
+        assert_eq!(process.context.env["target"], "prod");
+        assert_eq!(process.context.env["region"], "prod-region");
+        assert_eq!(process.context.env["layered"], "from-file");
+        assert_eq!(process.context.env["PUP_PROFILE"], "prod");
+        assert_eq!(process.context.global_env["PUP_PROFILE"], "prod");
+
+        let runner = process.runner("tests.builds.deployment").unwrap();
+        assert!(format!("{:?}", runner).starts_with(" Profile: prod\n"));
+
+        let process = PupProcess::load_from(test_context_process_path(), None).unwrap();
+        assert_eq!(process.manifest.tasks.len(), 2);
+        assert_eq!(process.context.env["foo"], "bar");
+        assert_eq!(process.context.env["region"], "shared-region");
+        assert_eq!(process.context.env["PUP_PROFILE"], "");
+    }
+
+    #[test]
+    fn test_invalid_profiles() {
+        let manifest: PupProcessManifest = serde_yaml::from_str(
+            "tasks: []\n\
+             workers_path: bin\n\
+             tasks_path: tasks\n\
+             environment: {}\n\
+             profiles:\n  \
+               a: { inherits: b }\n  \
+               b: { inherits: a }\n  \
+               c: { inherits: missing }\n",
+        )
+        .unwrap();
+        let err = manifest.resolve_profile("a").err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::InvalidProfile);
+        assert!(err.error_detail.contains("a -> b -> a"));
+
+        let err = manifest.resolve_profile("c").err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::InvalidProfile);
+        assert!(err.error_detail.contains("No profile named 'missing'"));
+    }
+
+    #[test]
+    fn test_load_extended_manifest() {
+        let process = PupProcess::load_from(join(test_context_folder(), "derived.yml"), None).unwrap();
+        assert_eq!(
+            process.manifest.tasks,
+            vec!["tests.builds.deployment", "tests.builds.bad", "tests.actions.nested"]
+        );
+        assert_eq!(process.context.env["foo"], "derived-foo");
+        assert_eq!(process.context.env["from_base"], "extra");
+        assert_eq!(process.context.env["from_extra"], "extra");
+        assert_eq!(process.manifest.secrets, vec!["BASE_TOKEN"]);
+        assert_eq!(process.context.max_depth, DEFAULT_MAX_DEPTH);
+        assert_eq!(process.context.tasks, vec![join(test_context_folder(), "tasks")]);
+        assert_eq!(
+            process.context.global_env["MANIFEST_HOME"],
+            test_context_folder().display().to_string()
+        );
+    }
+
+    #[test]
+    fn test_manifest_include_cycles() {
+        let path = join(test_context_folder(), join("shared", "cycle_a.yml"));
+        let err = PupProcess::load_from(path, None).err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::RecursiveManifest);
+        assert!(err.error_detail.contains("cycle_a.yml -> "));
+        assert!(err.error_detail.contains("cycle_b.yml -> "));
+    }
+
+    #[test]
+    fn test_unknown_keys_report_location() {
+        let manifest_path = test_output_path("unknown-keys.yml");
+        fs::write(&manifest_path, "tasks_path: tasks\nworkers_path: bin\nenviroment:\n  foo: bar\n").unwrap();
+        let err = PupProcess::load_from(&manifest_path, None).err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::InvalidYaml);
+        assert_eq!(err.location.as_ref().unwrap().line, 3);
+        assert!(err.error_detail.contains("unknown field `enviroment`"));
+        assert!(err.error_detail.ends_with("3 | enviroment:\n  | ^"));
+    }
+
     #[test]
     fn test_use_manifest_path() {
         let sample_process = test_context_process_path();
@@ -214,10 +756,86 @@ mod tests {
         assert_eq!(action.env["userthing"], "foouser -> foopass");
     }
 
+    #[test]
+    fn test_secrets_are_masked() {
+        let process = test_fixture();
+        assert_eq!(process.manifest.secrets, vec!("EXT_PASSWORD"));
+
+        // The secret, and the value rendered from it, are both masked
+        let secrets = &process.context.secrets;
+        assert_eq!(secrets.mask("password: foopass"), "password: ****");
+        assert_eq!(secrets.mask("user: foouser -> foopass"), "user: ****");
+        assert_eq!(secrets.mask("user: foouser"), "user: foouser");
+    }
+
     #[test]
     fn test_root_level_tasks() {
         let sample_process = test_context_process_path();
         let process = PupProcess::load_from(sample_process, None).unwrap();
         assert_eq!(process.manifest.tasks.len(), 2);
     }
+
+    #[test]
+    fn test_write_and_read_lock() {
+        let mut process = test_fixture();
+        process.lockfile = test_output_path("write.lock");
+        process.manifest.tasks = vec![String::from("tests.builds.deployment")];
+        process.write_lock().unwrap();
+
+        let lock = PupLockfile::try_from(&process.lockfile).unwrap();
+        let root = &lock.tasks["tests.builds.deployment"];
+        assert_eq!(root.steps["tests.builds.deployment"].version, "0.0.2");
+        assert_eq!(root.steps["tests.actions.runBuild"].version, "2.0.0");
+        assert_eq!(root.steps["tests.actions.prepareFolder"].hash.len(), 40);
+    }
+
+    #[test]
+    fn test_runner_honours_lock() {
+        let mut process = test_fixture();
+        process.lockfile = test_output_path("honour.lock");
+        process.manifest.tasks = vec![String::from("tests.builds.deployment")];
+        let mut lock = process.write_lock().unwrap();
+        lock.tasks
+            .get_mut("tests.builds.deployment")
+            .unwrap()
+            .steps
+            .get_mut("tests.actions.runBuild")
+            .unwrap()
+            .version = String::from("1.0.0");
+        lock.save(&process.lockfile).unwrap();
+
+        let runner = process.runner("tests.builds.deployment").unwrap();
+        let run_build = runner.tasks().children[0].children[1].external.take().unwrap();
+        assert_eq!(run_build.version.version, "1.0.0");
+
+        process.lock_mode = PupLockMode::Ignore;
+        let runner = process.runner("tests.builds.deployment").unwrap();
+        let run_build = runner.tasks().children[0].children[1].external.take().unwrap();
+        assert_eq!(run_build.version.version, "2.0.0");
+    }
+
+    #[test]
+    fn test_locked_mode_fails_on_changes() {
+        let mut process = test_fixture();
+        process.lockfile = test_output_path("locked.lock");
+        process.manifest.tasks = vec![String::from("tests.builds.deployment")];
+        let mut lock = process.write_lock().unwrap();
+
+        process.lock_mode = PupLockMode::Locked;
+        assert!(process.runner("tests.builds.deployment").is_ok());
+
+        lock.tasks
+            .get_mut("tests.builds.deployment")
+            .unwrap()
+            .steps
+            .get_mut("tests.actions.runBuild")
+            .unwrap()
+            .version = String::from("1.0.0");
+        lock.save(&process.lockfile).unwrap();
+        let err = process.runner("tests.builds.deployment").err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::LockfileMismatch);
+
+        let err = process.runner("tests.actions.nested").err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::LockfileMismatch);
+    }
 }
@@ -4,25 +4,47 @@ use crate::logger::get_logger;
 use crate::manifest::PupManifestStep;
 use crate::manifest::PupManifestVersion;
 use crate::runner::env::EnvHelper;
-use crate::runner::exec::exec;
+use crate::runner::exec::{exec, log_tail};
+use crate::runner::helpers::is_true;
 use crate::runner::ExecRequest;
 use crate::runner::ExecResult;
+use crate::runner::{PupEvent, PupEventSink};
+use crate::runner::{
+    PupFingerprintSource, PupLoadState, PupNodeStatus, PupRetryPolicy, PupRunState,
+};
 use crate::task::PupTask;
+use crate::utils::duration;
+use crate::utils::duration::parse_duration;
+use crate::utils::env_file;
+use crate::utils::hash::{hash_env, hash_lines};
 use crate::utils::path;
+use crate::utils::secrets;
 use crate::worker::{PupWorker, PupWorkerResult};
 use base_logging::Level;
 use base_logging::Logger;
 use dunce;
-use std::collections::HashMap;
-use std::env;
+use serde_json;
+use std::any::Any;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
+use std::mem;
+use std::panic;
+use std::panic::AssertUnwindSafe;
 use std::path::Path;
 use std::path::PathBuf;
-use std::thread::{spawn, JoinHandle};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::Instant;
 use time;
 use time::Duration;
 use time::Tm;
 
+/// The number of lines from the end of a worker's log to include in its error
+const LOG_TAIL_LINES: usize = 20;
+
 /// An action that involves executing an external command
 #[derive(Clone)]
 pub struct PupExternalAction {
@@ -40,6 +62,12 @@ pub struct PupExternalAction {
 
     /// The env to use for this specific instance
     pub env: HashMap<String, String>,
+
+    /// The input patterns for this instance, rendered with its env
+    pub inputs: Vec<String>,
+
+    /// The output patterns for this instance, rendered with its env
+    pub outputs: Vec<String>,
 }
 
 /// A task to be execute by the runner
@@ -51,11 +79,91 @@ pub struct PupAction {
     /// Was this a success?
     pub success: bool,
 
+    /// The identity of this action in the plan: task, version and effective env.
+    /// Actions with the same key are the same node, and only run once.
+    pub key: String,
+
     /// Details for an external
     pub external: Option<PupExternalAction>,
 
     /// Any child actions
     pub children: Vec<PupAction>,
+
+    /// Actions that reverse this one, if a later sibling fails
+    pub undo: Vec<PupAction>,
+
+    /// Actions that always run after the children and the worker for this action
+    pub finally: Vec<PupAction>,
+
+    /// Why this action is part of its parent
+    pub role: PupStepRole,
+
+    /// The step in the parent version that this action was loaded from, if any
+    pub step: Option<PupManifestStep>,
+
+    /// The longest this action and all of its children may run for, if limited
+    pub timeout: Option<std::time::Duration>,
+
+    /// How to retry the worker for this action if it fails, if at all
+    pub retry: Option<PupRetryPolicy>,
+
+    /// The number of times the worker was run
+    pub attempts: usize,
+
+    /// The exit code of the last run of the worker, if it ran
+    pub exit_code: Option<i32>,
+
+    /// If the worker for this action failed, why
+    pub error: Option<String>,
+
+    /// How long this action and its children took to run, if it ran
+    pub duration: Option<std::time::Duration>,
+
+    /// Why this action was not run, if it was skipped at run time
+    pub skip_reason: Option<String>,
+
+    /// Set if another reference to the same node already ran it in this run,
+    /// so this copy succeeded without doing anything here
+    pub shared_copy: bool,
+
+    /// The log of the output of the worker for this action, if any
+    pub log_path: Option<PathBuf>,
+
+    /// Steps of this action left out of the plan by their 'skip' or 'if' markers
+    pub skipped: Vec<PupSkippedStep>,
+
+    /// Undo steps of this action left out of the plan by their 'skip' or 'if' markers
+    pub undo_skipped: Vec<PupSkippedStep>,
+
+    /// Set if a later failure made this action run its undo steps
+    pub undone: bool,
+}
+
+/// A step that was left out of the plan when it was loaded
+#[derive(Debug, Clone)]
+pub struct PupSkippedStep {
+    /// The step reference, eg. foo.bar#1.0.0
+    pub step: String,
+
+    /// The marker that caused it, 'skip' or 'if'
+    pub marker: String,
+
+    /// The rendered value of the marker
+    pub token: String,
+}
+
+/// The part an action plays in its parent
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PupStepRole {
+    /// One of the steps of the parent version
+    Step,
+
+    /// An undo step for the step it belongs to
+    Undo,
+
+    /// A finally step of the parent version
+    Finally,
 }
 
 /// Options to use when
@@ -66,6 +174,77 @@ pub struct PupActionOptions {
 
     /// The set of distinct arguments to pass to the final executable.
     pub args: Vec<String>,
+
+    /// The number of independent steps to run at the same time, unless a
+    /// version sets its own limit. One job runs every step in order.
+    pub jobs: usize,
+
+    /// Prefix each line of worker output with the task that produced it.
+    pub prefix_output: bool,
+
+    /// The longest the whole run may take, if limited.
+    pub timeout: Option<std::time::Duration>,
+
+    /// The time every worker must finish by, if any.
+    /// The runner sets this from 'timeout', and each step with a timeout narrows it.
+    pub deadline: Option<Instant>,
+
+    /// If a step fails, keep running every other step that doesn't depend on it.
+    pub keep_going: bool,
+
+    /// If set, write the output of each worker to a log file in this folder.
+    pub log_folder: Option<PathBuf>,
+
+    /// If set, write a structured event for each step of the run here.
+    pub events: Option<PupEventSink>,
+
+    /// Set by the runner while it runs finally and undo steps.
+    /// Cleanup always runs: it is never shared with other steps, resumed, or skipped as up to date.
+    pub cleanup: bool,
+}
+
+impl Default for PupActionOptions {
+    fn default() -> Self {
+        return PupActionOptions {
+            dry_run: false,
+            args: Vec::new(),
+            jobs: 1,
+            prefix_output: false,
+            timeout: None,
+            deadline: None,
+            keep_going: false,
+            log_folder: None,
+            events: None,
+            cleanup: false,
+        };
+    }
+}
+
+impl PupActionOptions {
+    /// Return a copy of these options, with the deadline narrowed to 'timeout' from 'start'.
+    pub fn with_timeout(&self, timeout: std::time::Duration, start: Instant) -> PupActionOptions {
+        let mut rtn = self.clone();
+        let deadline = start + timeout;
+        rtn.deadline = match self.deadline {
+            Some(existing) if existing < deadline => Some(existing),
+            _ => Some(deadline),
+        };
+        return rtn;
+    }
+
+    /// Return a copy of these options for running finally and undo steps.
+    pub fn for_cleanup(&self) -> PupActionOptions {
+        let mut rtn = self.clone();
+        rtn.cleanup = true;
+        return rtn;
+    }
+
+    /// Write an event to the event stream, if there is one
+    pub fn emit(&self, event: PupEvent) {
+        if let Some(ref events) = self.events {
+            events.emit(event);
+        }
+    }
 }
 
 impl PupAction {
@@ -73,9 +252,26 @@ impl PupAction {
     pub fn new() -> PupAction {
         return PupAction {
             children: Vec::new(),
+            undo: Vec::new(),
+            finally: Vec::new(),
+            role: PupStepRole::Step,
             success: false,
             completed: false,
+            key: String::new(),
             external: None,
+            step: None,
+            timeout: None,
+            retry: None,
+            attempts: 0,
+            exit_code: None,
+            error: None,
+            duration: None,
+            skip_reason: None,
+            shared_copy: false,
+            log_path: None,
+            skipped: Vec::new(),
+            undo_skipped: Vec::new(),
+            undone: false,
         };
     }
 
@@ -87,12 +283,34 @@ impl PupAction {
         global_env: &HashMap<String, String>,
         parent_env: &HashMap<String, String>,
     ) -> Result<(), PupError> {
-        // TODO: Recursive runaway check here
+        let mut state = PupLoadState::new(None);
+        return self.load_with_state(context, name, global_env, parent_env, &mut state);
+    }
 
+    /// Load the task and all children, tracking the chain of name#version
+    /// identifiers from the root so recursive references fail instead of overflowing,
+    /// and recording the version each step resolves to.
+    pub fn load_with_state(
+        &mut self,
+        context: &PupContext,
+        name: &str,
+        global_env: &HashMap<String, String>,
+        parent_env: &HashMap<String, String>,
+        state: &mut PupLoadState,
+    ) -> Result<(), PupError> {
         // Load task and version
         let mut logger = get_logger();
         logger.log(Level::Debug, format!("Loading task: {}", name));
-        let maybe_task = context.load_task(name);
+        let maybe_task = match state.locked_version(name) {
+            Some(version_id) => {
+                logger.log(
+                    Level::Debug,
+                    format!("Using locked version: {} -> {}", name, version_id),
+                );
+                context.load_locked_task(name, &version_id)
+            }
+            None => context.load_task(name),
+        };
         if maybe_task.is_err() {
             logger.log(
                 Level::Debug,
@@ -105,6 +323,29 @@ impl PupAction {
             return Err(maybe_task.err().unwrap());
         }
         let (task, version) = maybe_task.unwrap();
+        state.resolved.insert(name, &version.version, &task.path);
+
+        // Check for runaway recursion before loading anything else
+        let ident = format!("{}#{}", task.name, version.version);
+        if let Some(offset) = state.chain.iter().position(|i| *i == ident) {
+            let mut cycle = state.chain[offset..].to_vec();
+            cycle.push(ident);
+            return Err(PupError::with_message(
+                PupErrorType::RecursiveTask,
+                &format!("Recursive task reference: {}", cycle.join(" -> ")),
+            ));
+        }
+        if state.chain.len() >= context.max_depth {
+            return Err(PupError::with_message(
+                PupErrorType::MaxDepthExceeded,
+                &format!(
+                    "Task nesting exceeds max depth of {}: {} -> {}",
+                    context.max_depth,
+                    state.chain.join(" -> "),
+                    ident
+                ),
+            ));
+        }
 
         // Load the worker for the task
         let maybe_worker = context.load_worker(&task.manifest.action);
@@ -122,44 +363,64 @@ impl PupAction {
         let worker = maybe_worker.unwrap();
 
         // Load children
-        let env_helper = EnvHelper::new(global_env);
+        let env_helper = EnvHelper::new(global_env)
+            .with_strict(context.strict)
+            .with_secrets(&context.secrets);
+        let mut steps = StepLoader {
+            context,
+            env_helper: &env_helper,
+            global_env,
+            name,
+            ident: &ident,
+            skipped: Vec::new(),
+        };
         for step in version.steps.iter() {
-            // Generate a combined env for this child
-            let env = match env_helper.extend_with_parent_env(&step.environment, parent_env) {
-                Ok(e) => e,
-                Err(err) => {
-                    logger.log(
-                        Level::Debug,
-                        format!("Failed to load task: {}: {}", name, err.description()),
-                    );
-                    return Err(err);
-                }
-            };
-
-            if self.should_skip_task(&env_helper, step, &env, name, &mut logger)? {
-                continue;
+            if let Some(child) =
+                steps.load(step, PupStepRole::Step, parent_env, state, &mut logger)?
+            {
+                self.children.push(child);
             }
+        }
+        for step in version.finally.iter() {
+            if let Some(child) =
+                steps.load(step, PupStepRole::Finally, parent_env, state, &mut logger)?
+            {
+                self.finally.push(child);
+            }
+        }
 
-            // Load the child with the rendered env group
-            logger.log(Level::Debug, format!("Loading child task: {}", step.step));
-            let mut child_action = PupAction::new();
-            child_action.load(context, &step.step, global_env, &env)?;
-            self.children.push(child_action);
+        self.skipped = steps.skipped;
+
+        // Render the incremental inputs and outputs
+        let mut inputs = Vec::new();
+        for pattern in version.inputs.iter() {
+            inputs.push(env_helper.process_env_variable(pattern, parent_env)?);
+        }
+        let mut outputs = Vec::new();
+        for pattern in version.outputs.iter() {
+            outputs.push(env_helper.process_env_variable(pattern, parent_env)?);
         }
 
-        // Configure self
+        // Configure self; references that run the task differently are different nodes
+        self.key = match self.step.as_ref().and_then(|step| step_settings_hash(step)) {
+            Some(settings) => format!("{}@{}+{}", ident, hash_env(parent_env), settings),
+            None => format!("{}@{}", ident, hash_env(parent_env)),
+        };
         self.external = Some(PupExternalAction {
             worker,
             task,
             version,
             result: PupWorkerResult {},
             env: parent_env.clone(),
+            inputs,
+            outputs,
         });
 
         Ok(())
     }
 
     /// 'skip' and 'if' are two special markers on steps to decide if they should execute in a plan.
+    /// Returns the marker that caused the step to be skipped, if any.
     fn should_skip_task(
         &self,
         env_helper: &EnvHelper,
@@ -167,7 +428,7 @@ impl PupAction {
         env: &HashMap<String, String>,
         name: &str,
         logger: &mut Logger,
-    ) -> Result<bool, PupError> {
+    ) -> Result<Option<PupSkippedStep>, PupError> {
         // Check if this child has a skip marker?
         if step.skip != "" {
             let skip_test = match env_helper.process_env_variable(&step.skip, &env) {
@@ -177,10 +438,10 @@ impl PupAction {
                         Level::Debug,
                         format!("Failed to load task: {}: {}", name, err.description()),
                     );
-                    return Err(err);
+                    return Err(err.with_context("skip"));
                 }
             };
-            if skip_test.len() > 0 && skip_test != "0" && skip_test.to_lowercase() != "false" {
+            if is_true(&skip_test) {
                 logger.log(
                     Level::Debug,
                     format!(
@@ -188,7 +449,11 @@ impl PupAction {
                         step.step, skip_test
                     ),
                 );
-                return Ok(true);
+                return Ok(Some(PupSkippedStep {
+                    step: step.step.clone(),
+                    marker: String::from("skip"),
+                    token: skip_test,
+                }));
             } else {
                 if skip_test.len() > 0 {
                     logger.log(
@@ -211,10 +476,10 @@ impl PupAction {
                         Level::Debug,
                         format!("Failed to load task: {}: {}", name, err.description()),
                     );
-                    return Err(err);
+                    return Err(err.with_context("if"));
                 }
             };
-            if skip_test.len() == 0 || skip_test == "0" || skip_test.to_lowercase() == "false" {
+            if !is_true(&skip_test) {
                 logger.log(
                     Level::Debug,
                     format!(
@@ -222,7 +487,11 @@ impl PupAction {
                         step.step, skip_test
                     ),
                 );
-                return Ok(true);
+                return Ok(Some(PupSkippedStep {
+                    step: step.step.clone(),
+                    marker: String::from("if"),
+                    token: skip_test,
+                }));
             } else {
                 if skip_test.len() > 0 {
                     logger.log(
@@ -236,15 +505,20 @@ impl PupAction {
             }
         }
 
-        return Ok(false);
+        return Ok(None);
     }
 
     /// Run this task and all child tasks
-    pub fn run(&mut self, logger: &mut Logger, options: &PupActionOptions) -> Result<(), PupError> {
+    pub fn run(
+        &mut self,
+        logger: &mut Logger,
+        options: &PupActionOptions,
+        state: &PupRunState,
+    ) -> Result<(), PupError> {
         if options.dry_run {
             self.info(logger, "Dryrun. No tasks will be executed", 1);
         }
-        return self.run_timed(logger, options, 1);
+        return self.run_timed(logger, options, state, 1);
     }
 
     /// Run this task and all child tasks, timed
@@ -252,29 +526,111 @@ impl PupAction {
         &mut self,
         logger: &mut Logger,
         options: &PupActionOptions,
+        state: &PupRunState,
         depth: usize,
     ) -> Result<(), PupError> {
+        // Shared nodes only run once per run, but cleanup always runs
+        let claimed = match options.cleanup {
+            true => None,
+            false => state.claim(&self.key),
+        };
+        match claimed {
+            Some(PupNodeStatus::Failed) => {
+                self.completed = true;
+                self.success = false;
+                let err = PupError::with_message(
+                    PupErrorType::WorkerFailed,
+                    &format!("Shared task failed: {}", self.key),
+                );
+                self.error = Some(err.summary());
+                return Err(err);
+            }
+            Some(status) => {
+                self.completed = true;
+                self.success = true;
+                let (reason, event_reason) = match status {
+                    PupNodeStatus::Resumed => ("succeeded in a previous run", "resumed"),
+                    _ => ("already completed", "completed"),
+                };
+                self.shared_copy = status == PupNodeStatus::Completed;
+                self.skip_reason = Some(reason.to_string());
+                options.emit(PupEvent::StepSkipped {
+                    step: self.name(),
+                    depth,
+                    reason: event_reason.to_string(),
+                    token: None,
+                });
+                if let Some(ref ext) = self.external {
+                    self.info(
+                        logger,
+                        &format!(
+                            "Skipping task: {} #{} ({})",
+                            ext.task.name, ext.version.version, reason
+                        ),
+                        depth,
+                    );
+                }
+                return Ok(());
+            }
+            None => {}
+        }
+
         let time_start = time::now();
-        let rtn = self.run_internal(logger, options, depth, time_start);
+        let rtn = match panic::catch_unwind(AssertUnwindSafe(|| {
+            return self.run_internal(logger, options, state, depth, time_start);
+        })) {
+            Ok(r) => r,
+            Err(payload) => {
+                // Release the key, so nothing waiting on it blocks forever
+                if !options.cleanup {
+                    let _ = state.finish(&self.key, false);
+                }
+                panic::resume_unwind(payload);
+            }
+        };
+        self.completed = true;
+        self.success = rtn.is_ok();
+        let rtn = match options.cleanup {
+            true => rtn,
+            false => rtn.and(state.finish(&self.key, self.success)),
+        };
         let time_stop = time::now();
         let task_duration = time_stop - time_start;
+        self.duration = task_duration.to_std().ok();
+        options.emit(PupEvent::StepFinished {
+            step: self.name(),
+            depth,
+            success: self.success,
+            duration_ms: task_duration.num_milliseconds(),
+            exit_code: self.exit_code,
+            attempts: self.attempts,
+            error: match rtn {
+                Err(ref err) => Some(err.summary()),
+                Ok(_) => None,
+            },
+        });
 
         let result = match rtn.is_err() {
             true => "FAILED",
             false => "Finished",
         };
 
+        let attempts = match self.attempts {
+            a if a > 1 => format!(", {} attempts", a),
+            _ => String::new(),
+        };
         match self.external {
             Some(ref ext) => {
                 self.info(
                     logger,
                     &format!(
-                        "{} task: {} #{} ({}, {})",
+                        "{} task: {} #{} ({}, {}{})",
                         result,
                         ext.task.name,
                         ext.version.version,
                         format_time(time_stop),
-                        format_duration(task_duration)
+                        format_duration(task_duration),
+                        attempts
                     ),
                     depth + 1,
                 );
@@ -301,6 +657,7 @@ impl PupAction {
         &mut self,
         logger: &mut Logger,
         options: &PupActionOptions,
+        state: &PupRunState,
         depth: usize,
         time_start: Tm,
     ) -> Result<(), PupError> {
@@ -326,104 +683,591 @@ impl PupAction {
             }
         }
 
+        options.emit(PupEvent::StepEntered {
+            step: self.name(),
+            depth,
+            role: self.role,
+        });
+        for skipped in self.skipped.iter() {
+            options.emit(PupEvent::StepSkipped {
+                step: skipped.step.clone(),
+                depth: depth + 1,
+                reason: skipped.marker.clone(),
+                token: Some(skipped.token.clone()),
+            });
+        }
+
+        // Cleanup gets the deadline of the parent, not the one this step narrows to
+        let cleanup_options = options.for_cleanup();
+
+        // Narrow the deadline to this step's own timeout
+        let limited: PupActionOptions;
+        let options = match self.timeout {
+            Some(timeout) => {
+                limited = options.with_timeout(timeout, Instant::now());
+                &limited
+            }
+            None => options,
+        };
+
         // Execute dependency steps first
-        for child in self.children.iter_mut() {
-            child.run_timed(logger, options, depth + 1)?;
+        let mut result = self.run_children(logger, options, state, depth);
+
+        // Now execute our own step, if required.
+        if result.is_ok() {
+            // Put the external back even if the worker panics, so the action keeps its name
+            let mut external = self.external.take();
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+                return match external {
+                    Some(ref mut ext) => self.run_worker(ext, logger, options, state, depth),
+                    None => Ok(()),
+                };
+            }));
+            self.external = external;
+            result = match outcome {
+                Ok(r) => r,
+                Err(payload) => panic::resume_unwind(payload),
+            };
+            if let Err(ref err) = result {
+                self.error = Some(err.summary());
+            }
+        }
+
+        // Undo the steps that succeeded if anything after them failed
+        if result.is_err() {
+            self.run_undo(logger, &cleanup_options, state, depth);
         }
 
-        // Modify any existing external path with the working folder
-        let mut external = self.external.take();
-        let result = match external {
-            Some(ref mut ext) => {
-                // Move to folder
+        // Cleanup steps always run
+        let cleanup = self.run_finally(logger, &cleanup_options, state, depth);
+        return result.and(cleanup);
+    }
+
+    /// Run every finally step, even if one of them fails, and return the first failure.
+    fn run_finally(
+        &mut self,
+        logger: &mut Logger,
+        options: &PupActionOptions,
+        state: &PupRunState,
+        depth: usize,
+    ) -> Result<(), PupError> {
+        let mut rtn = Ok(());
+        for action in self.finally.iter_mut() {
+            let result = action.run_timed(logger, options, state, depth + 1);
+            if rtn.is_ok() {
+                rtn = result;
+            }
+        }
+        return rtn;
+    }
+
+    /// Run the undo steps of every child that succeeded here, in reverse order,
+    /// once a later step or this action's own worker has failed.
+    /// A child that another reference to the same node ran is left for that one to undo.
+    /// A failed undo step is logged, but doesn't stop the rest.
+    fn run_undo(
+        &mut self,
+        logger: &mut Logger,
+        options: &PupActionOptions,
+        state: &PupRunState,
+        depth: usize,
+    ) {
+        for index in (0..self.children.len()).rev() {
+            let child = &self.children[index];
+            if !child.success || child.shared_copy {
+                continue;
+            }
+            if child.undo.len() == 0 && child.undo_skipped.len() == 0 {
+                continue;
+            }
+            self.info(
+                logger,
+                &format!("Undoing step: {}", self.children[index].name()),
+                depth + 1,
+            );
+            let child = &mut self.children[index];
+            child.undone = true;
+            for skipped in child.undo_skipped.iter() {
+                options.emit(PupEvent::StepSkipped {
+                    step: skipped.step.clone(),
+                    depth: depth + 2,
+                    reason: skipped.marker.clone(),
+                    token: Some(skipped.token.clone()),
+                });
+            }
+            for undo in child.undo.iter_mut() {
+                if let Err(err) = undo.run_timed(logger, options, state, depth + 2) {
+                    undo.info(
+                        logger,
+                        &format!("Failed to undo: {}", err.error_detail),
+                        depth + 2,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Invoke the worker for this action in the action folder
+    fn run_worker(
+        &mut self,
+        ext: &mut PupExternalAction,
+        logger: &mut Logger,
+        options: &PupActionOptions,
+        state: &PupRunState,
+        depth: usize,
+    ) -> Result<(), PupError> {
+        self.info(
+            logger,
+            &format!("Using: {}", path::display(&ext.version.path)),
+            depth + 1,
+        );
+        let action_path = self.resolve_action_folder(&ext.version.path, logger, depth)?;
+
+        // Skip the worker if nothing it uses has changed since it last succeeded
+        let fingerprint = match state.has_fingerprints() && !options.cleanup {
+            true => fingerprint_of(ext)?,
+            false => None,
+        };
+        if let Some(ref current) = fingerprint {
+            if state.is_up_to_date(&self.key, current) && fingerprint_source(ext).outputs_exist()? {
                 self.info(
                     logger,
-                    &format!("Using: {}", path::display(&ext.version.path)),
+                    &format!("Up to date: {} #{}", ext.task.name, ext.version.version),
                     depth + 1,
                 );
-                match self.try_run_cwd(&ext.version.path, logger, depth) {
-                    Ok(active_path) => {
-                        // This is the path the action is executing in, so it can find its
-                        // own resources, eg. powershell files, in the action path.
-                        ext.env.insert("ACTION_HOME".to_string(), active_path);
-                        Ok(())
-                    }
-                    Err(e) => Err(e),
-                }
+                self.skip_reason = Some(String::from("up to date"));
+                options.emit(PupEvent::StepSkipped {
+                    step: format!("{}#{}", ext.task.name, ext.version.version),
+                    depth,
+                    reason: String::from("up_to_date"),
+                    token: None,
+                });
+                return Ok(());
             }
-            _ => Ok(()),
-        };
-        if result.is_err() {
-            return result;
+            self.info(
+                logger,
+                &format!("Out of date: {} #{}", ext.task.name, ext.version.version),
+                depth + 1,
+            );
         }
 
-        // Now execute our own step, if required.
-        match external {
-            Some(ref ext) => {
-                // Invoke worker stream
-                if options.dry_run {
+        // This is the path the action is executing in, so it can find its
+        // own resources, eg. powershell files, in the action path.
+        ext.env.insert(
+            "ACTION_HOME".to_string(),
+            action_path.to_str().unwrap_or("NoPath").to_string(),
+        );
+
+        // Invoke worker stream
+        if options.dry_run {
+            self.info(
+                logger,
+                &format!(
+                    "Exec: (skipped) {} {}",
+                    path::display(&ext.worker.path),
+                    options.args.join(" ")
+                ),
+                depth + 1,
+            );
+            let mut keys: Vec<String> = ext.env.keys().map(|i| i.to_string()).collect();
+            keys.sort();
+            for key in keys.iter() {
+                self.info(
+                    logger,
+                    &format!("Env: {}: {}", key, secrets::redact(key, &ext.env[key])),
+                    depth + 2,
+                );
+            }
+            return Ok(());
+        }
+
+        // Run the worker, retrying it if the policy allows
+        let log_path = options.log_folder.as_ref().map(|folder| {
+            path::join(
+                folder,
+                format!(
+                    "{:03}-{}#{}.log",
+                    state.next_log_number(),
+                    ext.task.name,
+                    ext.version.version
+                ),
+            )
+        });
+        self.log_path = log_path.clone();
+        let policy = self.retry.clone().unwrap_or_default();
+        let mut delay = policy.delay;
+        self.attempts = 0;
+        loop {
+            self.attempts += 1;
+            let attempt_start = time::now();
+            options.emit(PupEvent::WorkerExec {
+                step: format!("{}#{}", ext.task.name, ext.version.version),
+                depth,
+                worker: path::display(&ext.worker.path),
+                working_dir: path::display(&action_path),
+                args: options.args.clone(),
+                attempt: self.attempts,
+            });
+            let exec_result =
+                self.exec_worker(ext, &action_path, &log_path, logger, options, depth);
+            let exit_code = exec_result.as_ref().ok().map(|r| r.return_code);
+            self.exit_code = exit_code;
+            let result = match exec_result {
+                Ok(ref r) if r.return_code == 0 => Ok(()),
+                Ok(r) => Err(PupError::with_message(
+                    PupErrorType::WorkerFailed,
+                    &format!("Worker returned exit code: {}", r.return_code),
+                )),
+                Err(err) => Err(err),
+            };
+            let result = match (result, log_path.as_ref()) {
+                (Err(err), Some(p)) => Err(err.with_log(p, log_tail(p, LOG_TAIL_LINES))),
+                (r, _) => r,
+            };
+            if policy.attempts > 1 {
+                self.info(
+                    logger,
+                    &format!(
+                        "Attempt {}/{} {} ({})",
+                        self.attempts,
+                        policy.attempts,
+                        if result.is_ok() {
+                            "succeeded"
+                        } else {
+                            "failed"
+                        },
+                        format_duration(time::now() - attempt_start)
+                    ),
+                    depth + 1,
+                );
+            }
+            match result {
+                Ok(_) => break,
+                Err(err) => {
+                    if self.attempts >= policy.attempts || !policy.should_retry(&err, exit_code) {
+                        return Err(err);
+                    }
+                    // Don't wait past the deadline; the next attempt then times out before starting
+                    let wait = match options.deadline {
+                        Some(deadline) => {
+                            let left = deadline.saturating_duration_since(Instant::now());
+                            if left < delay {
+                                left
+                            } else {
+                                delay
+                            }
+                        }
+                        None => delay,
+                    };
                     self.info(
                         logger,
-                        &format!(
-                            "Exec: (skipped) {} {}",
-                            path::display(&ext.worker.path),
-                            options.args.join(" ")
-                        ),
+                        &format!("Retrying in {}", duration::format_duration(wait)),
                         depth + 1,
                     );
-                    let mut keys: Vec<String> = ext.env.keys().map(|i| i.to_string()).collect();
-                    keys.sort();
-                    for key in keys.iter() {
-                        self.info(
-                            logger,
-                            &format!("Env: {}: {}", key, ext.env[key]),
-                            depth + 2,
-                        );
+                    sleep(wait);
+                    delay = policy.next_delay(delay);
+                }
+            }
+        }
+
+        return match fingerprint {
+            Some(ref f) => state.save_fingerprint(&self.key, f),
+            None => Ok(()),
+        };
+    }
+
+    /// Run the worker once. A worker that runs and exits with an error is not an Err.
+    fn exec_worker(
+        &self,
+        ext: &PupExternalAction,
+        action_path: &Path,
+        log_path: &Option<PathBuf>,
+        logger: &mut Logger,
+        options: &PupActionOptions,
+        depth: usize,
+    ) -> Result<ExecResult, PupError> {
+        self.info(
+            logger,
+            &format!(
+                "Exec: {} {}",
+                path::display(&ext.worker.path),
+                options.args.join(" ")
+            ),
+            depth + 1,
+        );
+        let output_prefix = match options.prefix_output {
+            true => Some(format!("{}#{}", ext.task.name, ext.version.version)),
+            false => None,
+        };
+        let timeout = match options.deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if deadline <= now {
+                    return Err(PupError::with_message(
+                        PupErrorType::WorkerTimedOut,
+                        &format!(
+                            "Timed out before starting: {} #{}",
+                            ext.task.name, ext.version.version
+                        ),
+                    ));
+                }
+                Some(deadline - now)
+            }
+            None => None,
+        };
+        return match try_run_task(
+            &ext.worker.path,
+            action_path,
+            output_prefix,
+            timeout,
+            log_path.clone(),
+            options,
+            &ext.env,
+        )
+        .join()
+        {
+            Ok(result) => match result {
+                Ok(exec_result) => Ok(exec_result),
+                Err(err) => match err.error_type {
+                    PupErrorType::WorkerTimedOut => Err(err),
+                    _ => Err(PupError::with_message(
+                        PupErrorType::WorkerFailed,
+                        &format!("Failed to execute worker: {:?}", err),
+                    )),
+                },
+            },
+            Err(err) => Err(PupError::with_message(
+                PupErrorType::WorkerFailed,
+                &format!("Failed to execute worker: {:?}", err),
+            )),
+        };
+    }
+
+    /// Return the fingerprint of this action's inputs, env and task folder,
+    /// or None if it doesn't declare any inputs or outputs.
+    pub fn fingerprint(&self) -> Result<Option<String>, PupError> {
+        return match self.external {
+            Some(ref ext) => fingerprint_of(ext),
+            None => Ok(None),
+        };
+    }
+
+    /// Run every child action.
+    /// If more than one job is allowed, each run of consecutive independent
+    /// children is run in parallel; all other children run one at a time, in order.
+    /// A failed child stops the run, unless it continues on failure, or the run keeps
+    /// going; in which case the remaining children run, and the first failure is returned.
+    fn run_children(
+        &mut self,
+        logger: &mut Logger,
+        options: &PupActionOptions,
+        state: &PupRunState,
+        depth: usize,
+    ) -> Result<(), PupError> {
+        let jobs = self.jobs(options);
+        let mut blocked: Option<PupError> = None;
+        let mut offset = 0;
+        while offset < self.children.len() {
+            let mut end = offset;
+            while jobs > 1 && end < self.children.len() && self.children[end].is_independent() {
+                end += 1;
+            }
+            let results = if end - offset > 1 {
+                self.run_children_parallel(offset, end, jobs, logger, options, state, depth)
+            } else {
+                end = offset + 1;
+                vec![(
+                    offset,
+                    self.children[offset].run_timed(logger, options, state, depth + 1),
+                )]
+            };
+            offset = end;
+
+            for (index, result) in results {
+                let err = match result {
+                    Ok(_) => {
+                        if options.dry_run {
+                            for undo in self.children[index].undo.iter() {
+                                self.info(
+                                    logger,
+                                    &format!("If a later step fails, undo with: {}", undo.name()),
+                                    depth + 2,
+                                );
+                            }
+                        }
+                        continue;
                     }
-                } else {
+                    Err(err) => err,
+                };
+                if self.children[index].continues_on_failure() {
                     self.info(
                         logger,
                         &format!(
-                            "Exec: {} {}",
-                            path::display(&ext.worker.path),
-                            options.args.join(" ")
+                            "Ignoring failed step: {} (continue_on_failure)",
+                            self.children[index].name()
                         ),
                         depth + 1,
                     );
-                    match try_run_task(&ext.worker.path, options, &ext.env).join() {
-                        Ok(result) => match result {
-                            Ok(exec_result) => {
-                                if exec_result.return_code != 0 {
-                                    return Err(PupError::with_message(
-                                        PupErrorType::WorkerFailed,
-                                        &format!(
-                                            "Worker returned exit code: {}",
-                                            exec_result.return_code
-                                        ),
-                                    ));
-                                }
-                                return Ok(());
-                            }
-                            Err(err) => {
-                                return Err(PupError::with_message(
-                                    PupErrorType::WorkerFailed,
-                                    &format!("Failed to execute worker: {:?}", err),
-                                ));
-                            }
-                        },
-                        Err(err) => {
-                            return Err(PupError::with_message(
-                                PupErrorType::WorkerFailed,
-                                &format!("Failed to execute worker: {:?}", err),
-                            ));
+                    continue;
+                }
+                if !options.keep_going {
+                    return Err(err);
+                }
+                if blocked.is_none() {
+                    blocked = Some(err);
+                }
+            }
+        }
+
+        return match blocked {
+            Some(err) => {
+                self.info(
+                    logger,
+                    &format!("Blocked: {} has failed steps", self.name()),
+                    depth + 1,
+                );
+                Err(err)
+            }
+            None => Ok(()),
+        };
+    }
+
+    /// Run the children in start..end on a pool of at most 'jobs' threads, and return the
+    /// result for each child that ran, by index, in order.
+    /// After any child fails no new children are started, unless the failure can be
+    /// ignored or the run keeps going.
+    fn run_children_parallel(
+        &mut self,
+        start: usize,
+        end: usize,
+        jobs: usize,
+        logger: &mut Logger,
+        options: &PupActionOptions,
+        state: &PupRunState,
+        depth: usize,
+    ) -> Vec<(usize, Result<(), PupError>)> {
+        let count = end - start;
+        let pool_size = cmp::min(jobs, count);
+        self.info(
+            logger,
+            &format!(
+                "Running {} independent steps, {} at a time",
+                count, pool_size
+            ),
+            depth + 1,
+        );
+
+        let pending: VecDeque<(usize, PupAction)> =
+            self.children.drain(start..end).enumerate().collect();
+        let queue = Arc::new(Mutex::new(pending));
+        let failed = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = channel();
+
+        let mut workers = Vec::new();
+        for _ in 0..pool_size {
+            let queue = queue.clone();
+            let failed = failed.clone();
+            let sender = sender.clone();
+            let state = state.clone();
+            let mut owned_options = options.clone();
+            owned_options.prefix_output = true;
+            workers.push(spawn(move || {
+                let mut logger = get_logger();
+                while !failed.load(Ordering::SeqCst) {
+                    let next = queue.lock().unwrap().pop_front();
+                    let (index, mut child) = match next {
+                        Some(n) => n,
+                        None => break,
+                    };
+                    let result = match panic::catch_unwind(AssertUnwindSafe(|| {
+                        return child.run_timed(&mut logger, &owned_options, &state, depth + 1);
+                    })) {
+                        Ok(r) => r,
+                        Err(payload) => {
+                            child.completed = true;
+                            child.success = false;
+                            let message = format!(
+                                "Step {} panicked: {}",
+                                child.name(),
+                                panic_message(&payload)
+                            );
+                            child.error = Some(message.clone());
+                            Err(PupError::with_message(PupErrorType::WorkerFailed, &message))
                         }
                     };
+                    if result.is_err() && !child.continues_on_failure() && !owned_options.keep_going
+                    {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+                    let _ = sender.send((index, child, result));
                 }
-            }
-            None => {}
+            }));
+        }
+        drop(sender);
+
+        // Collect results
+        let mut finished: Vec<Option<PupAction>> = (0..count).map(|_| None).collect();
+        let mut rtn = Vec::new();
+        for (index, child, result) in receiver.iter() {
+            rtn.push((start + index, result));
+            finished[index] = Some(child);
+        }
+        for worker in workers {
+            let _ = worker.join();
         }
+        rtn.sort_by_key(|r| r.0);
 
-        return Ok(());
+        // Put every child back in its original place, including any never started
+        for (index, child) in queue.lock().unwrap().drain(..) {
+            finished[index] = Some(child);
+        }
+        let restored: Vec<PupAction> = finished
+            .into_iter()
+            .map(|c| c.expect("Every parallel step is returned, even if it panicked"))
+            .collect();
+        self.children.splice(start..start, restored);
+
+        return rtn;
+    }
+
+    /// The number of independent children this action may run at the same time.
+    fn jobs(&self, options: &PupActionOptions) -> usize {
+        return match self.external {
+            Some(ref ext) if ext.version.jobs > 0 => ext.version.jobs,
+            _ => options.jobs,
+        };
+    }
+
+    /// Every action this one owns: its children, then its undo steps, then its finally steps.
+    pub fn all_children(&self) -> Vec<&PupAction> {
+        return self
+            .children
+            .iter()
+            .chain(self.undo.iter())
+            .chain(self.finally.iter())
+            .collect();
+    }
+
+    /// Was this action loaded from a step that continues on failure?
+    pub fn continues_on_failure(&self) -> bool {
+        return self
+            .step
+            .as_ref()
+            .map(|s| s.continue_on_failure)
+            .unwrap_or(false);
+    }
+
+    /// The name#version of the task for this action
+    pub fn name(&self) -> String {
+        return match self.external {
+            Some(ref ext) => format!("{}#{}", ext.task.name, ext.version.version),
+            None => String::from("(root)"),
+        };
+    }
+
+    /// Was this action loaded from a step marked as independent of its siblings?
+    fn is_independent(&self) -> bool {
+        return self.step.as_ref().map(|s| s.independent).unwrap_or(false);
     }
 
     fn info(&self, logger: &mut Logger, message: &str, depth: usize) {
@@ -431,43 +1275,183 @@ impl PupAction {
         logger.log(Level::Info, format!("{} {}", prefix, message));
     }
 
-    fn try_run_cwd(
+    /// Find the folder to run the worker in.
+    /// The worker process is started in this folder; pup's own working directory never changes.
+    fn resolve_action_folder(
         &self,
         path: &Path,
         logger: &mut Logger,
         depth: usize,
-    ) -> Result<String, PupError> {
+    ) -> Result<PathBuf, PupError> {
+        if !path::exists(path) {
+            return Err(PupError::with_message(
+                PupErrorType::MissingActionFolder,
+                &format!("Missing action folder: {:?}", path),
+            ));
+        }
+
         // Convert UNC paths on windows, because it breaks things.
         // Seriously. Powershell for example won't run without a signed certificate.
-        let path_to_use = match dunce::canonicalize(path) {
-            Ok(p) => p,
+        return match dunce::canonicalize(path) {
+            Ok(p) => Ok(p),
             Err(err) => {
                 self.info(
                     logger,
                     &format!("Error converting path: {}", err.description()),
                     depth + 1,
                 );
-                PathBuf::from(path)
+                Ok(PathBuf::from(path))
             }
         };
+    }
+}
 
-        return match env::set_current_dir(&path_to_use) {
-            Ok(_) => Ok(path_to_use.to_str().unwrap_or("NoPath").to_string()),
-            Err(err) => Err(PupError::with_error(
-                PupErrorType::MissingActionFolder,
-                &format!("Failed to set current dir to: {:?}", path),
-                err,
-            )),
+/// Loads the steps of a single version
+struct StepLoader<'a> {
+    context: &'a PupContext,
+    env_helper: &'a EnvHelper,
+    global_env: &'a HashMap<String, String>,
+    name: &'a str,
+    ident: &'a str,
+    skipped: Vec<PupSkippedStep>,
+}
+
+impl<'a> StepLoader<'a> {
+    /// Load a single step, and its undo steps, or return None if the step is skipped.
+    fn load(
+        &mut self,
+        step: &PupManifestStep,
+        role: PupStepRole,
+        parent_env: &HashMap<String, String>,
+        state: &mut PupLoadState,
+        logger: &mut Logger,
+    ) -> Result<Option<PupAction>, PupError> {
+        // Generate a combined env for this child
+        let context = format!("Step {} in {}", step.step, self.ident);
+        let env = match self.step_env(step, parent_env) {
+            Ok(e) => e,
+            Err(err) => {
+                logger.log(
+                    Level::Debug,
+                    format!("Failed to load task: {}: {}", self.name, err.description()),
+                );
+                return Err(err.with_context(&context));
+            }
         };
+
+        let mut child_action = PupAction::new();
+        let skip = child_action
+            .should_skip_task(self.env_helper, step, &env, self.name, logger)
+            .map_err(|err| err.with_context(&context))?;
+        if let Some(skipped) = skip {
+            self.skipped.push(skipped);
+            return Ok(None);
+        }
+
+        // Load the child with the rendered env group
+        logger.log(Level::Debug, format!("Loading child task: {}", step.step));
+        child_action.role = role;
+        child_action.step = Some(step.clone());
+        if step.timeout != "" {
+            child_action.timeout = Some(parse_duration(&step.timeout)?);
+        }
+        if let Some(ref retry) = step.retry {
+            child_action.retry = Some(PupRetryPolicy::try_from(retry)?);
+        }
+        state.chain.push(self.ident.to_string());
+        let loaded =
+            child_action.load_with_state(self.context, &step.step, self.global_env, &env, state);
+        state.chain.pop();
+        loaded?;
+
+        // Undo steps run in place of this step, with the same env; any skipped stay with them
+        let skipped = mem::replace(&mut self.skipped, Vec::new());
+        for undo_step in step.undo.iter() {
+            if let Some(undo) = self.load(undo_step, PupStepRole::Undo, &env, state, logger)? {
+                child_action.undo.push(undo);
+            }
+        }
+        child_action.undo_skipped = mem::replace(&mut self.skipped, skipped);
+        return Ok(Some(child_action));
     }
+
+    /// The env of a step is its env_file, over its environment, over the parent env
+    fn step_env(
+        &self,
+        step: &PupManifestStep,
+        parent_env: &HashMap<String, String>,
+    ) -> Result<HashMap<String, String>, PupError> {
+        let mut source = step.environment.clone();
+        if step.env_file != "" {
+            let file = self
+                .env_helper
+                .process_env_variable(&step.env_file, parent_env)
+                .map_err(|err| err.with_context("env_file"))?;
+            source.extend(env_file::read_env_file(&env_file::resolve(
+                self.global_env,
+                &file,
+            ))?);
+        }
+        return self.env_helper.extend_with_parent_env(&source, parent_env);
+    }
+}
+
+fn fingerprint_source(ext: &PupExternalAction) -> PupFingerprintSource<'_> {
+    return PupFingerprintSource {
+        task_path: &ext.task.path,
+        version_path: &ext.version.path,
+        env: &ext.env,
+        inputs: &ext.inputs,
+        outputs: &ext.outputs,
+    };
+}
+
+/// A digest of the settings of a step that change how its task runs, if it has any
+fn step_settings_hash(step: &PupManifestStep) -> Option<String> {
+    let mut lines = Vec::new();
+    if step.timeout != "" {
+        lines.push(format!("timeout {}", step.timeout));
+    }
+    if let Some(ref retry) = step.retry {
+        lines.push(format!(
+            "retry {}",
+            serde_json::to_string(retry).unwrap_or_default()
+        ));
+    }
+    if step.continue_on_failure {
+        lines.push(String::from("continue_on_failure"));
+    }
+    for undo in step.undo.iter() {
+        lines.push(format!(
+            "undo {}",
+            serde_json::to_string(undo).unwrap_or_default()
+        ));
+    }
+    if lines.len() == 0 {
+        return None;
+    }
+    return Some(hash_lines(&lines));
+}
+
+fn fingerprint_of(ext: &PupExternalAction) -> Result<Option<String>, PupError> {
+    let source = fingerprint_source(ext);
+    if !source.is_incremental() {
+        return Ok(None);
+    }
+    return Ok(Some(source.fingerprint()?));
 }
 
 fn try_run_task(
     binary_path: &Path,
+    working_dir: &Path,
+    output_prefix: Option<String>,
+    timeout: Option<std::time::Duration>,
+    log_path: Option<PathBuf>,
     options: &PupActionOptions,
     env: &HashMap<String, String>,
 ) -> JoinHandle<Result<ExecResult, PupError>> {
     let owned_path = PathBuf::from(binary_path);
+    let owned_working_dir = PathBuf::from(working_dir);
     let owned_options = options.clone();
     let owned_env = env.clone();
     return spawn(move || {
@@ -475,10 +1459,25 @@ fn try_run_task(
             env: owned_env,
             binary_path: owned_path,
             args: owned_options.args,
+            working_dir: owned_working_dir,
+            output_prefix,
+            timeout,
+            log_path,
         });
     });
 }
 
+/// The message a panic was raised with, if it has one
+fn panic_message(payload: &Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        return message.to_string();
+    }
+    if let Some(message) = payload.downcast_ref::<String>() {
+        return message.clone();
+    }
+    return String::from("unknown error");
+}
+
 fn format_duration(d: Duration) -> String {
     let mut seconds = d.num_seconds();
     let minutes: i64 = seconds / 60;
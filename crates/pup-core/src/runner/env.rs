@@ -1,5 +1,8 @@
-use crate::PupError;
-use handlebars::Handlebars;
+use crate::runner::helpers::register_helpers;
+use crate::utils::secrets::PupSecrets;
+use crate::{PupError, PupErrorType};
+use handlebars::template::{Parameter, TemplateElement};
+use handlebars::{Handlebars, Template, TemplateRenderError};
 use std::collections::HashMap;
 use std::env;
 
@@ -8,23 +11,43 @@ pub struct EnvHelper {
     global: HashMap<String, String>,
     ambient: Option<HashMap<String, String>>,
     renderer: Handlebars,
+    secrets: PupSecrets,
 }
 
 impl EnvHelper {
     pub fn new(global_env: &HashMap<String, String>) -> EnvHelper {
+        let mut renderer = Handlebars::new();
+        register_helpers(&mut renderer);
         return EnvHelper {
             global: global_env.clone(),
             ambient: None,
-            renderer: Handlebars::new(),
+            renderer,
+            secrets: PupSecrets::new(),
         };
     }
 
+    /// In strict mode, rendering a template that uses an undefined variable fails.
+    pub fn with_strict(mut self, strict: bool) -> EnvHelper {
+        self.renderer.set_strict_mode(strict);
+        return self;
+    }
+
+    /// Track the values rendered from secrets in these secrets, so they are masked.
+    pub fn with_secrets(mut self, secrets: &PupSecrets) -> EnvHelper {
+        self.secrets = secrets.clone();
+        return self;
+    }
+
     pub fn process_env_variable(
         &self,
         source: &str,
         parent_env: &HashMap<String, String>,
     ) -> Result<String, PupError> {
-        let resolved = self.renderer.render_template(source, parent_env)?;
+        let resolved = self
+            .renderer
+            .render_template(source, parent_env)
+            .map_err(|err| self.render_error(source, parent_env, err))?;
+        self.secrets.track_render(source, parent_env, &resolved);
         return Ok(resolved);
     }
 
@@ -38,10 +61,13 @@ impl EnvHelper {
 
         // Render each child key, using the parent array
         for key in source.keys() {
-            let new_value = self.process_env_variable(&source[key], parent_env)?;
+            let new_value = self
+                .process_env_variable(&source[key], parent_env)
+                .map_err(|err| err.with_context(&format!("environment {}", key)))?;
             rtn.insert(key.to_string(), new_value);
         }
 
+        self.secrets.track_env(&rtn);
         return Ok(rtn);
     }
 
@@ -59,9 +85,89 @@ impl EnvHelper {
             rtn.insert(key.to_string(), new_value);
         }
 
+        self.secrets.track_env(&rtn);
         return Ok(rtn);
     }
 
+    fn render_error(
+        &self,
+        source: &str,
+        parent_env: &HashMap<String, String>,
+        err: TemplateRenderError,
+    ) -> PupError {
+        // In strict mode a render fails on a variable not in the env, so look for it there.
+        let missing = match err {
+            TemplateRenderError::RenderError(_) if self.renderer.strict_mode() => {
+                match Template::compile(source) {
+                    Ok(template) => self.find_missing(&template, parent_env),
+                    Err(_) => None,
+                }
+            }
+            _ => None,
+        };
+        return match missing {
+            Some(key) => PupError::with_message(
+                PupErrorType::UndefinedVariable,
+                &format!("Undefined variable {} in template '{}'", key, source),
+            ),
+            None => PupError::from(err),
+        };
+    }
+
+    /// Find the first variable the template uses that is not in the env, if any.
+    /// Like the renderer, this ignores the value passed to 'default'.
+    fn find_missing(&self, template: &Template, env: &HashMap<String, String>) -> Option<String> {
+        return template
+            .elements
+            .iter()
+            .filter_map(|element| self.missing_in_element(element, env))
+            .next();
+    }
+
+    fn missing_in_element(
+        &self,
+        element: &TemplateElement,
+        env: &HashMap<String, String>,
+    ) -> Option<String> {
+        let helper = match element {
+            TemplateElement::HTMLExpression(param) => return self.missing_in_param(param, env),
+            TemplateElement::Expression(helper) | TemplateElement::HelperBlock(helper) => helper,
+            _ => return None,
+        };
+
+        let name = helper.name.as_name();
+        let missing = match name {
+            Some(name) if self.renderer.get_helper(name).is_some() => match name {
+                "default" => None,
+                _ => helper
+                    .params
+                    .iter()
+                    .chain(helper.hash.values())
+                    .filter_map(|param| self.missing_in_param(param, env))
+                    .next(),
+            },
+            _ => self.missing_in_param(&helper.name, env),
+        };
+        if missing.is_some() {
+            return missing;
+        }
+        return helper
+            .template
+            .iter()
+            .chain(helper.inverse.iter())
+            .filter_map(|template| self.find_missing(template, env))
+            .next();
+    }
+
+    fn missing_in_param(&self, param: &Parameter, env: &HashMap<String, String>) -> Option<String> {
+        return match param {
+            Parameter::Name(name) if is_context_path(name) || env.contains_key(name) => None,
+            Parameter::Name(name) => Some(name.to_string()),
+            Parameter::Subexpression(sub) => self.missing_in_element(&sub.element, env),
+            Parameter::Literal(_) => None,
+        };
+    }
+
     pub fn ambient_state(&mut self) -> &HashMap<String, String> {
         match self.ambient {
             Some(ref s) => s,
@@ -80,3 +186,11 @@ impl EnvHelper {
         }
     }
 }
+
+/// Is this a path into the block context, like 'this' or '@index', rather than an env variable?
+fn is_context_path(name: &str) -> bool {
+    return name == "this"
+        || name.starts_with("this.")
+        || name.starts_with('@')
+        || name.starts_with("../");
+}
@@ -0,0 +1,144 @@
+use crate::errors::PupError;
+use crate::runner::PupStepRole;
+use crate::utils::secrets;
+use serde_json;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use time;
+
+/// A single entry in the structured event stream of a run
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum PupEvent {
+    /// The runner has started running the root tasks
+    RunStarted {
+        run_id: Option<String>,
+        tasks: Vec<String>,
+        dry_run: bool,
+    },
+
+    /// Every step that will be visited by this run, in plan order
+    PlanResolved { steps: Vec<PupEventStep> },
+
+    /// A step has started, before any of its children
+    StepEntered {
+        step: String,
+        depth: usize,
+        role: PupStepRole,
+    },
+
+    /// A step was not run; 'reason' is 'skip' or 'if' for a marker on the step,
+    /// with the rendered token, or 'resumed', 'completed' or 'up_to_date'.
+    StepSkipped {
+        step: String,
+        depth: usize,
+        reason: String,
+        token: Option<String>,
+    },
+
+    /// The worker for a step is being executed
+    WorkerExec {
+        step: String,
+        depth: usize,
+        worker: String,
+        working_dir: String,
+        args: Vec<String>,
+        attempt: usize,
+    },
+
+    /// A step has finished, after its children and finally steps
+    StepFinished {
+        step: String,
+        depth: usize,
+        success: bool,
+        duration_ms: i64,
+        exit_code: Option<i32>,
+        attempts: usize,
+        error: Option<String>,
+    },
+
+    /// The runner has finished every root task it could
+    RunFinished {
+        success: bool,
+        duration_ms: i64,
+        failures: Vec<String>,
+    },
+}
+
+/// A step in the resolved plan
+#[derive(Debug, Clone, Serialize)]
+pub struct PupEventStep {
+    pub step: String,
+    pub depth: usize,
+    pub role: PupStepRole,
+}
+
+/// Somewhere to write events to, as newline delimited json.
+/// Clones share the same output, so parallel steps can emit events safely.
+#[derive(Clone)]
+pub struct PupEventSink {
+    output: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl PupEventSink {
+    /// Write events to any output
+    pub fn new(output: Box<dyn Write + Send>) -> PupEventSink {
+        return PupEventSink {
+            output: Arc::new(Mutex::new(output)),
+        };
+    }
+
+    /// Write events to 'path', or to stdout if the path is '-'.
+    /// Stdout is then kept for events alone; logs and worker output go to stderr instead.
+    pub fn open(path: &str) -> Result<PupEventSink, PupError> {
+        if path == "-" {
+            return Ok(PupEventSink::new(take_stdout()?));
+        }
+        let file = File::create(Path::new(path))?;
+        return Ok(PupEventSink::new(Box::new(file)));
+    }
+
+    /// Write a single event, with the current time.
+    /// A broken event stream is not a reason to fail a run, so write errors are ignored.
+    pub fn emit(&self, event: PupEvent) {
+        let mut value = match serde_json::to_value(&event) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        if let Some(fields) = value.as_object_mut() {
+            let now = time::now_utc();
+            fields.insert(String::from("time"), serde_json::Value::from(format!("{}", now.rfc3339())));
+        }
+        secrets::active().mask_json(&mut value);
+        let mut output = self.output.lock().unwrap();
+        let _ = writeln!(output, "{}", value.to_string());
+        let _ = output.flush();
+    }
+}
+
+/// Move the real stdout out of the way, so anything else written to it lands on stderr
+#[cfg(unix)]
+fn take_stdout() -> Result<Box<dyn Write + Send>, PupError> {
+    use std::os::unix::io::FromRawFd;
+    io::stdout().flush()?;
+    unsafe {
+        let events = libc::dup(libc::STDOUT_FILENO);
+        if events < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            let err = io::Error::last_os_error();
+            libc::close(events);
+            return Err(err.into());
+        }
+        return Ok(Box::new(File::from_raw_fd(events)));
+    }
+}
+
+#[cfg(not(unix))]
+fn take_stdout() -> Result<Box<dyn Write + Send>, PupError> {
+    return Ok(Box::new(io::stdout()));
+}
@@ -1,9 +1,41 @@
 use crate::errors::{PupError, PupErrorType};
+use crate::utils::duration::format_duration;
 use crate::utils::path;
+use crate::utils::secrets;
 use std::collections::HashMap;
 use std::error::Error;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::str;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+#[cfg(unix)]
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::ptr;
+#[cfg(unix)]
+use std::sync::Once;
+
+/// How long to keep copying output after the worker's process group is killed.
+/// A process that left the group may hold its output open for much longer than this.
+const OUTPUT_DRAIN: Duration = Duration::from_millis(500);
+
+/// How often an output stream checks if the worker has exited while it waits for output
+const OUTPUT_POLL: Duration = Duration::from_millis(50);
 
 pub struct ExecRequest {
     /// The set of environment variables to add
@@ -14,6 +46,20 @@ pub struct ExecRequest {
 
     /// The set of arguments to use
     pub args: Vec<String>,
+
+    /// The folder to run the binary in.
+    /// This only applies to the spawned process, not to the current process.
+    pub working_dir: PathBuf,
+
+    /// If set, prefix each line of output with this, so output from
+    /// workers running at the same time can be told apart.
+    pub output_prefix: Option<String>,
+
+    /// If set, kill the worker and every process it started if it runs longer than this.
+    pub timeout: Option<Duration>,
+
+    /// If set, append the output of the worker to this file, as well as the console.
+    pub log_path: Option<PathBuf>,
 }
 
 #[derive(Debug)]
@@ -23,18 +69,72 @@ pub struct ExecResult {
 }
 
 pub fn exec(request: ExecRequest) -> Result<ExecResult, PupError> {
-    match Command::new(&request.binary_path)
+    let log = match request.log_path {
+        Some(ref p) => Some(Arc::new(Mutex::new(open_log(p)?))),
+        None => None,
+    };
+
+    let mut command = Command::new(&request.binary_path);
+    command
         .args(&request.args)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .envs(&request.env)
-        .spawn()
-    {
+        .current_dir(&request.working_dir)
+        .stdout(output_mode(&request))
+        .stderr(output_mode(&request))
+        .envs(&request.env);
+    // Reserve the worker's place before it starts, so a signal can't arrive before it's tracked
+    let mut group = None;
+    if request.timeout.is_some() {
+        group = Some(WorkerGroup::reserve()?);
+        new_process_group(&mut command);
+    }
+    match command.spawn() {
         Ok(mut cmd) => {
-            let return_code = cmd.wait()?;
-            return Ok(ExecResult {
-                return_code: return_code.code().unwrap(),
-            });
+            if let Some(ref mut group) = group {
+                group.track(&cmd);
+            }
+            let exited = Arc::new(AtomicBool::new(false));
+            let mut streams = Vec::new();
+            if let Some(stdout) = cmd.stdout.take() {
+                streams.push(stream_output(stdout, &request.output_prefix, false, &log, &exited));
+            }
+            if let Some(stderr) = cmd.stderr.take() {
+                streams.push(stream_output(stderr, &request.output_prefix, true, &log, &exited));
+            }
+            let return_code = match request.timeout {
+                Some(timeout) => wait_with_timeout(&mut cmd, timeout),
+                None => cmd.wait().map(Some),
+            };
+            drop(group);
+
+            // Everything the worker wrote is read before returning, so its log is complete;
+            // but if it was killed, what it left behind is only waited for briefly.
+            exited.store(true, Ordering::SeqCst);
+            match return_code {
+                Ok(Some(_)) => drain_output(streams, None),
+                _ => drain_output(streams, Some(OUTPUT_DRAIN)),
+            }
+            return match return_code {
+                Ok(Some(status)) => Ok(ExecResult {
+                    return_code: status.code().unwrap_or(-1),
+                }),
+                Ok(None) => Err(PupError::with_message(
+                    PupErrorType::WorkerTimedOut,
+                    &format!(
+                        "Worker timed out after {}: {}",
+                        format_duration(request.timeout.unwrap()),
+                        path::display(&request.binary_path)
+                    ),
+                )),
+                Err(err) => Err(PupError::with_error(
+                    PupErrorType::WorkerFailed,
+                    &format!(
+                        "Unable to wait for worker: {}: {}",
+                        path::display(&request.binary_path),
+                        err.description()
+                    ),
+                    err,
+                )),
+            };
         }
         Err(err) => {
             return Err(PupError::with_error(
@@ -49,3 +149,504 @@ pub fn exec(request: ExecRequest) -> Result<ExecResult, PupError> {
         }
     };
 }
+
+/// Wait for the worker to exit, or kill its whole process group once the timeout passes.
+/// Returns None if the worker was killed. If waiting fails, the group is killed too.
+fn wait_with_timeout(cmd: &mut Child, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let started = Instant::now();
+    loop {
+        match cmd.try_wait() {
+            Ok(Some(status)) => return Ok(Some(status)),
+            Ok(None) => {}
+            Err(err) => {
+                kill_process_group(cmd);
+                return Err(err);
+            }
+        }
+        if started.elapsed() >= timeout {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    kill_process_group(cmd);
+    let _ = cmd.wait();
+    return Ok(None);
+}
+
+/// Start the worker in its own process group, so it can be killed along with its children.
+/// The group is no longer in the foreground, so it doesn't get the terminal's SIGINT;
+/// see forward_signals_to_workers.
+#[cfg(unix)]
+fn new_process_group(command: &mut Command) {
+    unsafe {
+        command.pre_exec(|| {
+            libc::setpgid(0, 0);
+            return Ok(());
+        });
+    }
+}
+
+/// On windows the process tree is killed with taskkill instead.
+#[cfg(not(unix))]
+fn new_process_group(_command: &mut Command) {}
+
+/// Pass SIGINT and SIGTERM on to every running worker with a timeout, then stop this process
+/// as the signal would have. Workers with a timeout run in their own process group, so without
+/// this a Ctrl-C stops pup but leaves them running.
+/// This replaces the process's own handlers for good, so it is for programs like the pup binary
+/// that want that; a program embedding pup can signal the workers itself.
+#[cfg(unix)]
+pub fn forward_signals_to_workers() {
+    FORWARD_SIGNALS.call_once(|| unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, ptr::null_mut());
+        libc::sigaction(libc::SIGTERM, &action, ptr::null_mut());
+    });
+}
+
+#[cfg(not(unix))]
+pub fn forward_signals_to_workers() {}
+
+#[cfg(unix)]
+static FORWARD_SIGNALS: Once = Once::new();
+
+/// The most workers with a timeout that may run at once
+pub const MAX_WORKER_GROUPS: usize = 64;
+
+/// A slot held for a worker that is being spawned, so its process group isn't known yet
+#[cfg(unix)]
+const SPAWNING: i32 = -1;
+
+/// The process groups of running timed workers; 0 is an empty slot.
+/// These are atomics, because the signal handler must not take a lock.
+#[cfg(unix)]
+static WORKER_GROUPS: [AtomicI32; MAX_WORKER_GROUPS] = {
+    const EMPTY: AtomicI32 = AtomicI32::new(0);
+    [EMPTY; MAX_WORKER_GROUPS]
+};
+
+/// The last signal to arrive, kept for a worker that was being spawned when it did
+#[cfg(unix)]
+static PENDING_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Send a signal pup got to every worker process group, then stop pup as the signal would have.
+/// If a worker is still being spawned, the signal is left for it to pass on once it's tracked.
+#[cfg(unix)]
+extern "C" fn forward_signal(signal: libc::c_int) {
+    PENDING_SIGNAL.store(signal, Ordering::SeqCst);
+    if WORKER_GROUPS.iter().any(|group| group.load(Ordering::SeqCst) == SPAWNING) {
+        return;
+    }
+    for group in WORKER_GROUPS.iter() {
+        let pgid = group.load(Ordering::SeqCst);
+        if pgid > 0 {
+            unsafe {
+                libc::kill(-pgid, signal);
+            }
+        }
+    }
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = libc::SIG_DFL;
+        libc::sigaction(signal, &action, ptr::null_mut());
+        libc::raise(signal);
+    }
+}
+
+/// Forward a signal that arrived while workers were being spawned, once none are
+#[cfg(unix)]
+fn forward_pending_signal() {
+    if WORKER_GROUPS.iter().any(|group| group.load(Ordering::SeqCst) == SPAWNING) {
+        return;
+    }
+    let signal = PENDING_SIGNAL.swap(0, Ordering::SeqCst);
+    if signal != 0 {
+        forward_signal(signal);
+    }
+}
+
+/// A worker process group that gets the signals sent to pup while this is alive.
+struct WorkerGroup {
+    #[cfg(unix)]
+    slot: usize,
+}
+
+impl WorkerGroup {
+    /// Hold a place for a worker that is about to be spawned.
+    /// Fails if MAX_WORKER_GROUPS workers with a timeout are running already.
+    #[cfg(unix)]
+    fn reserve() -> Result<WorkerGroup, PupError> {
+        let slot = WORKER_GROUPS.iter().position(|group| {
+            return group.compare_exchange(0, SPAWNING, Ordering::SeqCst, Ordering::SeqCst).is_ok();
+        });
+        return match slot {
+            Some(slot) => Ok(WorkerGroup { slot }),
+            None => Err(PupError::with_message(
+                PupErrorType::FailedToSpawnWorker,
+                &format!(
+                    "Unable to spawn worker: more than {} workers with a timeout are running at once",
+                    MAX_WORKER_GROUPS
+                ),
+            )),
+        };
+    }
+
+    #[cfg(not(unix))]
+    fn reserve() -> Result<WorkerGroup, PupError> {
+        return Ok(WorkerGroup {});
+    }
+
+    /// Start forwarding signals to the worker's process group
+    #[cfg(unix)]
+    fn track(&mut self, cmd: &Child) {
+        WORKER_GROUPS[self.slot].store(cmd.id() as i32, Ordering::SeqCst);
+        forward_pending_signal();
+    }
+
+    #[cfg(not(unix))]
+    fn track(&mut self, _cmd: &Child) {}
+}
+
+#[cfg(unix)]
+impl Drop for WorkerGroup {
+    fn drop(&mut self) {
+        WORKER_GROUPS[self.slot].store(0, Ordering::SeqCst);
+        forward_pending_signal();
+    }
+}
+
+#[cfg(unix)]
+fn kill_process_group(cmd: &mut Child) {
+    unsafe {
+        libc::kill(-(cmd.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = cmd.kill();
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(cmd: &mut Child) {
+    let _ = Command::new("taskkill")
+        .args(&["/F", "/T", "/PID", &cmd.id().to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    let _ = cmd.kill();
+}
+
+/// Return the last 'count' lines of a log file.
+pub fn log_tail(log_path: &Path, count: usize) -> Vec<String> {
+    let mut raw = String::new();
+    if let Ok(mut fp) = File::open(log_path) {
+        let _ = fp.read_to_string(&mut raw);
+    }
+    let lines: Vec<String> = raw.lines().map(|l| l.to_string()).collect();
+    let start = if lines.len() > count { lines.len() - count } else { 0 };
+    return lines[start..].to_vec();
+}
+
+fn open_log(log_path: &Path) -> Result<File, PupError> {
+    if let Some(parent) = log_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    return Ok(OpenOptions::new().create(true).append(true).open(log_path)?);
+}
+
+/// Prefixed or logged output is piped back through pup, otherwise it goes straight to the console.
+fn output_mode(request: &ExecRequest) -> Stdio {
+    if request.output_prefix.is_some() || request.log_path.is_some() {
+        return Stdio::piped();
+    }
+    return Stdio::inherit();
+}
+
+/// Wait for every output stream to copy all of the worker's output, for up to 'limit' if set.
+/// A stream still open after that is left to finish on its own.
+fn drain_output(streams: Vec<Receiver<()>>, limit: Option<Duration>) {
+    let until = limit.map(|limit| Instant::now() + limit);
+    for stream in streams {
+        let drained = match until {
+            Some(until) => {
+                let now = Instant::now();
+                now < until && stream.recv_timeout(until - now).is_ok()
+            }
+            None => stream.recv().is_ok(),
+        };
+        if !drained {
+            return;
+        }
+    }
+}
+
+/// A worker output stream
+#[cfg(unix)]
+trait OutputSource: Read + AsRawFd + Send + 'static {}
+#[cfg(unix)]
+impl<T: Read + AsRawFd + Send + 'static> OutputSource for T {}
+
+#[cfg(not(unix))]
+trait OutputSource: Read + Send + 'static {}
+#[cfg(not(unix))]
+impl<T: Read + Send + 'static> OutputSource for T {}
+
+/// Wait up to 'wait' for output to read, or for the stream to close
+#[cfg(unix)]
+fn wait_for_output<R: OutputSource>(source: &R, wait: Duration) -> bool {
+    let mut poll = libc::pollfd {
+        fd: source.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    return unsafe { libc::poll(&mut poll, 1, wait.as_millis() as libc::c_int) } != 0;
+}
+
+/// Without poll, just wait for the stream to close.
+#[cfg(not(unix))]
+fn wait_for_output<R: OutputSource>(_source: &R, _wait: Duration) -> bool {
+    return true;
+}
+
+/// Echo a worker output stream as it arrives, with a prefix on each line if any,
+/// and copy it to the log if any. Returns a channel that gets a message once the stream
+/// closes, or once the worker has exited and everything it wrote has been copied.
+/// Anything written after that, by a process the worker left running, is still echoed.
+fn stream_output<R: OutputSource>(
+    mut source: R,
+    prefix: &Option<String>,
+    is_stderr: bool,
+    log: &Option<Arc<Mutex<File>>>,
+    exited: &Arc<AtomicBool>,
+) -> Receiver<()> {
+    let mut echo = OutputEcho {
+        prefix: match prefix {
+            Some(p) => format!("[{}] ", p),
+            None => String::new(),
+        },
+        is_stderr,
+        log: log.clone(),
+        at_line_start: true,
+    };
+    let exited = exited.clone();
+    let (done, receiver) = channel();
+    spawn(move || {
+        let mut pending: Vec<u8> = Vec::new();
+        let mut chunk = [0u8; 4096];
+        let mut drained = false;
+        loop {
+            // Once the worker has exited, all it wrote is waiting to be read; so if nothing is,
+            // anything still to come is from a process it left running.
+            if !drained {
+                let has_exited = exited.load(Ordering::SeqCst);
+                if !wait_for_output(&source, OUTPUT_POLL) {
+                    if has_exited {
+                        let _ = done.send(());
+                        drained = true;
+                    }
+                    continue;
+                }
+            }
+            let count = match source.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(count) => count,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+            pending.extend_from_slice(&chunk[..count]);
+            while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = pending.drain(..end + 1).collect();
+                let line = String::from_utf8_lossy(&line[..end]);
+                echo.write(line.trim_end_matches('\r'), true);
+            }
+
+            // Echo a partial line, eg. a prompt, without waiting for the rest of it;
+            // but keep back a character that is split across reads.
+            let complete = match str::from_utf8(&pending) {
+                Err(ref err) if err.error_len().is_none() => err.valid_up_to(),
+                _ => pending.len(),
+            };
+            if complete > 0 {
+                let part: Vec<u8> = pending.drain(..complete).collect();
+                echo.write(&String::from_utf8_lossy(&part), false);
+            }
+        }
+        if pending.len() > 0 || !echo.at_line_start {
+            echo.write(&String::from_utf8_lossy(&pending), true);
+        }
+        let _ = done.send(());
+    });
+    return receiver;
+}
+
+/// Copies worker output to the console and the log
+struct OutputEcho {
+    prefix: String,
+    is_stderr: bool,
+    log: Option<Arc<Mutex<File>>>,
+    at_line_start: bool,
+}
+
+impl OutputEcho {
+    /// Write some output, and end the line if 'end_line' is set.
+    /// The prefix goes in front of the first output on each line.
+    fn write(&mut self, text: &str, end_line: bool) {
+        let masked = secrets::mask(text);
+        let end = if end_line { "\n" } else { "" };
+        let prefix = if self.at_line_start { self.prefix.as_str() } else { "" };
+        let output = format!("{}{}{}", prefix, masked, end);
+        if self.is_stderr {
+            let stderr = io::stderr();
+            let mut handle = stderr.lock();
+            let _ = handle.write_all(output.as_bytes());
+            let _ = handle.flush();
+        } else {
+            let stdout = io::stdout();
+            let mut handle = stdout.lock();
+            let _ = handle.write_all(output.as_bytes());
+            let _ = handle.flush();
+        }
+        if let Some(ref file) = self.log {
+            let _ = write!(file.lock().unwrap(), "{}{}", masked, end);
+        }
+        self.at_line_start = end_line;
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{exec, log_tail, ExecRequest};
+    use crate::errors::PupErrorType;
+    use crate::testing::test_output_path;
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_exec_kills_process_group_on_timeout() {
+        let started = Instant::now();
+        let err = exec(ExecRequest {
+            env: HashMap::new(),
+            binary_path: PathBuf::from("/bin/sh"),
+            args: vec![String::from("-c"), String::from("sleep 5 & sleep 5")],
+            working_dir: env::temp_dir(),
+            output_prefix: Some(String::from("sleep")),
+            timeout: Some(Duration::from_millis(200)),
+            log_path: None,
+        })
+        .err()
+        .unwrap();
+        assert_eq!(err.error_type, PupErrorType::WorkerTimedOut);
+
+        // The background sleep holds the output pipe open, so this only
+        // returns early if the whole process group was killed.
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_exec_within_timeout() {
+        let result = exec(ExecRequest {
+            env: HashMap::new(),
+            binary_path: PathBuf::from("/bin/sh"),
+            args: vec![String::from("-c"), String::from("exit 3")],
+            working_dir: env::temp_dir(),
+            output_prefix: None,
+            timeout: Some(Duration::from_secs(10)),
+            log_path: None,
+        })
+        .unwrap();
+        assert_eq!(result.return_code, 3);
+
+        // The host's own signal handling is left alone
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(libc::SIGINT, std::ptr::null(), &mut action);
+            assert_eq!(action.sa_sigaction, libc::SIG_DFL);
+        }
+    }
+
+    #[test]
+    fn test_exec_writes_output_to_log() {
+        let log_path = test_output_path("exec.log");
+        let _ = fs::remove_file(&log_path);
+        let result = exec(ExecRequest {
+            env: HashMap::new(),
+            binary_path: PathBuf::from("/bin/sh"),
+            args: vec![
+                String::from("-c"),
+                String::from("echo one; echo two; echo three >&2; exit 1"),
+            ],
+            working_dir: env::temp_dir(),
+            output_prefix: None,
+            timeout: None,
+            log_path: Some(log_path.clone()),
+        })
+        .unwrap();
+        assert_eq!(result.return_code, 1);
+
+        let mut lines = log_tail(&log_path, 10);
+        lines.sort();
+        assert_eq!(lines, vec!("one", "three", "two"));
+        assert_eq!(log_tail(&log_path, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_exec_logs_partial_and_invalid_output() {
+        let log_path = test_output_path("exec-bytes.log");
+        let _ = fs::remove_file(&log_path);
+        let result = exec(ExecRequest {
+            env: HashMap::new(),
+            binary_path: PathBuf::from("/bin/sh"),
+            args: vec![String::from("-c"), String::from("printf 'a\\377b\\nprompt: '")],
+            working_dir: env::temp_dir(),
+            output_prefix: None,
+            timeout: None,
+            log_path: Some(log_path.clone()),
+        })
+        .unwrap();
+        assert_eq!(result.return_code, 0);
+        assert_eq!(log_tail(&log_path, 10), vec!("a\u{FFFD}b", "prompt: "));
+    }
+
+    #[test]
+    fn test_exec_does_not_wait_for_background_output() {
+        let started = Instant::now();
+        let result = exec(ExecRequest {
+            env: HashMap::new(),
+            binary_path: PathBuf::from("/bin/sh"),
+            args: vec![String::from("-c"), String::from("sleep 5 & echo started")],
+            working_dir: env::temp_dir(),
+            output_prefix: None,
+            timeout: None,
+            log_path: Some(test_output_path("exec-background.log")),
+        })
+        .unwrap();
+        assert_eq!(result.return_code, 0);
+
+        // The background sleep holds the output pipe open after the worker exits
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_exec_logs_all_output_before_returning() {
+        let log_path = test_output_path("exec-complete.log");
+        let _ = fs::remove_file(&log_path);
+        let script = "sleep 5 & i=0; while [ $i -lt 2000 ]; do echo line $i; i=$((i+1)); done";
+        let result = exec(ExecRequest {
+            env: HashMap::new(),
+            binary_path: PathBuf::from("/bin/sh"),
+            args: vec![String::from("-c"), String::from(script)],
+            working_dir: env::temp_dir(),
+            output_prefix: None,
+            timeout: None,
+            log_path: Some(log_path.clone()),
+        })
+        .unwrap();
+        assert_eq!(result.return_code, 0);
+
+        let log = fs::read_to_string(&log_path).unwrap();
+        assert_eq!(log.lines().last(), Some("line 1999"));
+    }
+}
@@ -0,0 +1,144 @@
+use crate::errors::{PupError, PupErrorType};
+use crate::utils::hash::{hash_env, hash_file, hash_folder_except, hash_lines};
+use crate::utils::path;
+use glob::glob;
+use serde_yaml;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The name of the fingerprint database, in the process state folder
+pub const FINGERPRINTS_NAME: &str = "fingerprints.yml";
+
+/// The fingerprint of the last successful run of every incremental action
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PupFingerprints {
+    /// The fingerprint for each action, by action key
+    #[serde(default)]
+    pub actions: BTreeMap<String, String>,
+}
+
+impl PupFingerprints {
+    pub fn try_from(db_path: &Path) -> Result<Self, PupError> {
+        return Self::read_db(db_path).map_err(|err| {
+            return PupError::with_error(
+                PupErrorType::InvalidRunState,
+                &format!(
+                    "Unable to read fingerprints: {}: {:?}",
+                    path::display(db_path),
+                    err.description()
+                ),
+                err,
+            );
+        });
+    }
+
+    fn read_db(db_path: &Path) -> Result<Self, PupError> {
+        let mut fp = File::open(&db_path)?;
+        let mut raw = String::new();
+        fp.read_to_string(&mut raw)?;
+        return Ok(serde_yaml::from_str(&raw)?);
+    }
+
+    /// Write the database to disk, creating the folder for it if required
+    pub fn save(&self, db_path: &Path) -> Result<(), PupError> {
+        if let Some(parent) = db_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let raw = serde_yaml::to_string(self)?;
+        let mut fp = File::create(db_path)?;
+        fp.write_all(raw.as_bytes())?;
+        fp.write_all(b"\n")?;
+        return Ok(());
+    }
+}
+
+/// The files an incremental action reads and writes
+pub struct PupFingerprintSource<'a> {
+    /// The task folder; any change to it changes the fingerprint
+    pub task_path: &'a Path,
+
+    /// The folder the patterns are relative to
+    pub version_path: &'a Path,
+
+    /// The rendered env for the action
+    pub env: &'a HashMap<String, String>,
+
+    /// The rendered input patterns
+    pub inputs: &'a [String],
+
+    /// The rendered output patterns
+    pub outputs: &'a [String],
+}
+
+impl<'a> PupFingerprintSource<'a> {
+    /// Only actions that declare inputs or outputs are incremental
+    pub fn is_incremental(&self) -> bool {
+        return self.inputs.len() > 0 || self.outputs.len() > 0;
+    }
+
+    /// Return the fingerprint of the inputs, env and task folder.
+    /// Outputs are left out, so that writing them doesn't change the fingerprint, and so
+    /// is ACTION_HOME, which is only added to the env when the worker runs.
+    pub fn fingerprint(&self) -> Result<String, PupError> {
+        let mut outputs = Vec::new();
+        for pattern in self.outputs.iter() {
+            outputs.extend(self.expand(pattern)?);
+        }
+
+        let mut env = self.env.clone();
+        env.remove("ACTION_HOME");
+        let mut lines = vec![
+            format!("env {}", hash_env(&env)),
+            format!("task {}", hash_folder_except(self.task_path, &outputs)?),
+        ];
+        for pattern in self.inputs.iter() {
+            lines.push(format!("pattern {}", pattern));
+            let mut files = self.expand(pattern)?;
+            files.sort();
+            for file in files.iter().filter(|f| f.is_file()) {
+                let relative = file.strip_prefix(self.version_path).unwrap_or(file);
+                lines.push(format!("input {} {}", relative.display(), hash_file(file)?));
+            }
+        }
+        return Ok(hash_lines(&lines));
+    }
+
+    /// Does every output pattern match at least one path?
+    pub fn outputs_exist(&self) -> Result<bool, PupError> {
+        for pattern in self.outputs.iter() {
+            if self.expand(pattern)?.len() == 0 {
+                return Ok(false);
+            }
+        }
+        return Ok(true);
+    }
+
+    /// Return every path matching a pattern relative to the version folder
+    fn expand(&self, pattern: &str) -> Result<Vec<PathBuf>, PupError> {
+        let full_pattern = path::join(self.version_path, pattern);
+        let paths = match glob(&full_pattern.to_string_lossy()) {
+            Ok(p) => p,
+            Err(err) => {
+                return Err(PupError::with_message(
+                    PupErrorType::InvalidGlobPattern,
+                    &format!("Invalid pattern: {}: {}", pattern, err),
+                ));
+            }
+        };
+        let mut rtn = Vec::new();
+        for entry in paths {
+            match entry {
+                Ok(p) => rtn.push(p),
+                Err(err) => {
+                    return Err(PupError::from(io::Error::new(err.error().kind(), err.to_string())));
+                }
+            }
+        }
+        return Ok(rtn);
+    }
+}
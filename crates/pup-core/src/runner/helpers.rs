@@ -0,0 +1,197 @@
+use crate::utils::path;
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, JsonValue as Json, RenderContext, RenderError,
+    ScopedJson,
+};
+use std::env::consts;
+use std::path::Path;
+
+/// Register the built-in helpers for env, 'skip' and 'if' templates, eg.
+/// {{default FOO "x"}}, {{eq OS_NAME "linux"}} or {{join MANIFEST_HOME "bin"}}
+pub fn register_helpers(renderer: &mut Handlebars) {
+    renderer.register_helper("default", Box::new(default));
+    renderer.register_helper("eq", Box::new(StrictParams(eq)));
+    renderer.register_helper("ne", Box::new(StrictParams(ne)));
+    renderer.register_helper("and", Box::new(StrictParams(and)));
+    renderer.register_helper("or", Box::new(StrictParams(or)));
+    renderer.register_helper("not", Box::new(StrictParams(not)));
+    renderer.register_helper("upper", Box::new(StrictParams(upper)));
+    renderer.register_helper("lower", Box::new(StrictParams(lower)));
+    renderer.register_helper("replace", Box::new(StrictParams(replace)));
+    renderer.register_helper("trim", Box::new(StrictParams(trim)));
+    renderer.register_helper("join", Box::new(StrictParams(join)));
+    renderer.register_helper("dirname", Box::new(StrictParams(dirname)));
+    renderer.register_helper("basename", Box::new(StrictParams(basename)));
+    renderer.register_helper("os", Box::new(StrictParams(PlatformHelper { value: consts::OS })));
+    renderer.register_helper("arch", Box::new(StrictParams(PlatformHelper { value: consts::ARCH })));
+}
+
+/// Handlebars only checks bare {{FOO}} references in strict mode, so
+/// this fails on an undefined variable passed to a helper too. 'default' is not wrapped.
+struct StrictParams<H: HelperDef>(H);
+
+impl<H: HelperDef> HelperDef for StrictParams<H> {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        if r.strict_mode() {
+            if let Some(missing) = h.params().iter().find(|p| p.is_value_missing()) {
+                return Err(RenderError::strict_error(missing.path()));
+            }
+        }
+        return self.0.call_inner(h, r, ctx, rc);
+    }
+}
+
+/// Is a rendered value true? Empty values, '0' and 'false' are false.
+/// This is the same rule the 'skip' and 'if' markers use.
+pub fn is_true(value: &str) -> bool {
+    return value.len() > 0 && value != "0" && value.to_lowercase() != "false";
+}
+
+fn is_truthy(value: &Json) -> bool {
+    return match value {
+        Json::Null => false,
+        Json::Bool(b) => *b,
+        _ => is_true(&as_string(value)),
+    };
+}
+
+/// Env values are all strings, but literals in templates may be numbers or booleans
+fn as_string(value: &Json) -> String {
+    return match value {
+        Json::Null => String::new(),
+        Json::String(s) => s.to_string(),
+        other => other.to_string(),
+    };
+}
+
+handlebars_helper!(default: |value: Json, fallback: Json| {
+    match as_string(value) {
+        ref v if v.len() > 0 => v.to_string(),
+        _ => as_string(fallback),
+    }
+});
+handlebars_helper!(eq: |a: Json, b: Json| as_string(a) == as_string(b));
+handlebars_helper!(ne: |a: Json, b: Json| as_string(a) != as_string(b));
+handlebars_helper!(and: |a: Json, b: Json| is_truthy(a) && is_truthy(b));
+handlebars_helper!(or: |a: Json, b: Json| is_truthy(a) || is_truthy(b));
+handlebars_helper!(not: |a: Json| !is_truthy(a));
+handlebars_helper!(upper: |s: Json| as_string(s).to_uppercase());
+handlebars_helper!(lower: |s: Json| as_string(s).to_lowercase());
+handlebars_helper!(replace: |s: Json, from: str, to: str| as_string(s).replace(from, to));
+handlebars_helper!(trim: |s: Json| as_string(s).trim().to_string());
+handlebars_helper!(join: |a: Json, b: Json| {
+    path::join(as_string(a), as_string(b)).display().to_string()
+});
+handlebars_helper!(dirname: |p: Json| {
+    Path::new(&as_string(p)).parent().map(|d| d.display().to_string()).unwrap_or_default()
+});
+handlebars_helper!(basename: |p: Json| {
+    Path::new(&as_string(p)).file_name().map(|f| f.to_string_lossy().to_string()).unwrap_or_default()
+});
+
+/// {{os}} renders the current platform, eg. linux; {{os "windows"}} renders if it matches.
+struct PlatformHelper {
+    value: &'static str,
+}
+
+impl HelperDef for PlatformHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let result = match h.param(0) {
+            Some(expected) => Json::from(as_string(expected.value()) == self.value),
+            None => Json::from(self.value),
+        };
+        return Ok(Some(ScopedJson::Derived(result)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::PupErrorType;
+    use crate::runner::env::EnvHelper;
+    use std::collections::HashMap;
+    use std::env::consts;
+
+    fn render(template: &str) -> String {
+        let mut env = HashMap::new();
+        env.insert(String::from("NAME"), String::from(" Pup "));
+        env.insert(String::from("EMPTY"), String::new());
+        env.insert(String::from("ENABLED"), String::from("true"));
+        env.insert(String::from("DISABLED"), String::from("false"));
+        env.insert(String::from("FILE"), String::from("/opt/pup/bin/pup.tar.gz"));
+        let helper = EnvHelper::new(&HashMap::new());
+        return helper.process_env_variable(template, &env).unwrap();
+    }
+
+    #[test]
+    fn test_default_helper() {
+        assert_eq!(render("{{default MISSING \"x\"}}"), "x");
+        assert_eq!(render("{{default EMPTY \"x\"}}"), "x");
+        assert_eq!(render("{{default ENABLED \"x\"}}"), "true");
+    }
+
+    #[test]
+    fn test_comparison_helpers() {
+        assert_eq!(render("{{eq ENABLED \"true\"}}"), "true");
+        assert_eq!(render("{{ne ENABLED \"true\"}}"), "false");
+        assert_eq!(render("{{and ENABLED DISABLED}}"), "false");
+        assert_eq!(render("{{or ENABLED DISABLED}}"), "true");
+        assert_eq!(render("{{not DISABLED}}"), "true");
+        assert_eq!(render("{{not MISSING}}"), "true");
+        assert_eq!(render("{{and ENABLED (not EMPTY)}}"), "true");
+    }
+
+    #[test]
+    fn test_string_helpers() {
+        assert_eq!(render("{{upper NAME}}"), " PUP ");
+        assert_eq!(render("{{lower (trim NAME)}}"), "pup");
+        assert_eq!(render("{{replace FILE \".tar.gz\" \".zip\"}}"), "/opt/pup/bin/pup.zip");
+    }
+
+    #[test]
+    fn test_path_helpers() {
+        assert_eq!(render("{{basename FILE}}"), "pup.tar.gz");
+        assert_eq!(render("{{basename (dirname FILE)}}"), "bin");
+        assert_eq!(render("{{basename (join (dirname FILE) \"other\")}}"), "other");
+    }
+
+    #[test]
+    fn test_strict_helper_params() {
+        let mut env = HashMap::new();
+        env.insert(String::from("ENABLED"), String::from("true"));
+        let helper = EnvHelper::new(&HashMap::new()).with_strict(true);
+        assert_eq!(helper.process_env_variable("{{eq ENABLED \"true\"}}", &env).unwrap(), "true");
+        assert_eq!(helper.process_env_variable("{{default MISSING \"x\"}}", &env).unwrap(), "x");
+
+        let err = helper.process_env_variable("{{eq MISSING \"true\"}}", &env).err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::UndefinedVariable);
+        assert!(err.error_detail.contains("MISSING"));
+
+        let err = helper.process_env_variable("{{upper (lower MISSING)}}", &env).err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::UndefinedVariable);
+        assert!(err.error_detail.contains("Undefined variable MISSING"));
+
+        // Other errors are not mistaken for undefined variables
+        let err = helper.process_env_variable("{{replace ENABLED}}", &env).err().unwrap();
+        assert_ne!(err.error_type, PupErrorType::UndefinedVariable);
+    }
+
+    #[test]
+    fn test_platform_helpers() {
+        assert_eq!(render("{{os}}"), consts::OS);
+        assert_eq!(render("{{arch}}"), consts::ARCH);
+        assert_eq!(render(&format!("{{{{os \"{}\"}}}}", consts::OS)), "true");
+        assert_eq!(render("{{os \"not-an-os\"}}"), "false");
+    }
+}
@@ -0,0 +1,205 @@
+use crate::errors::PupError;
+use crate::runner::{PupAction, PupStepRole};
+use crate::utils::secrets;
+use crate::utils::secrets::PupSecrets;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+/// A single testcase in the report
+struct JunitCase {
+    classname: String,
+    name: String,
+    seconds: f64,
+    outcome: JunitOutcome,
+    output: Option<String>,
+}
+
+enum JunitOutcome {
+    Passed,
+    Failed(String),
+    Skipped(String),
+}
+
+/// Write a JUnit XML report of a run, with one testsuite for each root task,
+/// and one testcase for each step in it.
+pub fn write_junit(
+    report_path: &Path,
+    names: &[String],
+    roots: &[PupAction],
+) -> Result<(), PupError> {
+    let secrets = secrets::active();
+    let mut raw = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n");
+    for (name, root) in names.iter().zip(roots.iter()) {
+        let mut cases = Vec::new();
+        collect_cases(root, &mut cases);
+        raw.push_str(&render_suite(name, &cases, &secrets));
+    }
+    raw.push_str("</testsuites>\n");
+
+    if let Some(parent) = report_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut fp = File::create(report_path)?;
+    fp.write_all(raw.as_bytes())?;
+    return Ok(());
+}
+
+fn collect_cases(action: &PupAction, cases: &mut Vec<JunitCase>) {
+    // Undo steps are only part of the report if they were needed
+    if action.role == PupStepRole::Undo && !action.completed {
+        return;
+    }
+
+    if let Some(ref ext) = action.external {
+        let outcome = if let Some(ref reason) = action.skip_reason {
+            JunitOutcome::Skipped(reason.to_string())
+        } else if !action.completed {
+            JunitOutcome::Skipped(String::from("not run"))
+        } else if !action.success {
+            JunitOutcome::Failed(match action.error {
+                Some(ref err) => err.to_string(),
+                None => String::from("A step it depends on failed"),
+            })
+        } else {
+            JunitOutcome::Passed
+        };
+        cases.push(JunitCase {
+            classname: ext.task.name.clone(),
+            name: ext.version.version.clone(),
+            seconds: action.duration.map(|d| d.as_millis() as f64 / 1000.0).unwrap_or(0.0),
+            outcome,
+            output: action
+                .log_path
+                .as_ref()
+                .and_then(|p| fs::read_to_string(p).ok()),
+        });
+    }
+
+    // Steps left out of the plan by a marker; undo steps only if the undo steps ran
+    let undo_skipped = match action.undone {
+        true => action.undo_skipped.as_slice(),
+        false => &[],
+    };
+    for skipped in action.skipped.iter().chain(undo_skipped.iter()) {
+        let mut parts = skipped.step.splitn(2, '#');
+        cases.push(JunitCase {
+            classname: parts.next().unwrap_or("").to_string(),
+            name: parts.next().unwrap_or("").to_string(),
+            seconds: 0.0,
+            outcome: JunitOutcome::Skipped(format!(
+                "{} token was: {}",
+                skipped.marker, skipped.token
+            )),
+            output: None,
+        });
+    }
+
+    for child in action.all_children() {
+        collect_cases(child, cases);
+    }
+}
+
+/// Render a testsuite, masking secrets in every value before it is escaped
+fn render_suite(name: &str, cases: &[JunitCase], secrets: &PupSecrets) -> String {
+    let masked = |value: &str| escape(&secrets.mask(value));
+    let failures = cases
+        .iter()
+        .filter(|c| match c.outcome {
+            JunitOutcome::Failed(_) => true,
+            _ => false,
+        })
+        .count();
+    let skipped = cases
+        .iter()
+        .filter(|c| match c.outcome {
+            JunitOutcome::Skipped(_) => true,
+            _ => false,
+        })
+        .count();
+    let seconds: f64 = cases.iter().map(|c| c.seconds).sum();
+
+    let mut raw = format!(
+        "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+        masked(name),
+        cases.len(),
+        failures,
+        skipped,
+        seconds
+    );
+    for case in cases.iter() {
+        raw.push_str(&format!(
+            "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\">\n",
+            masked(&case.classname),
+            masked(&case.name),
+            case.seconds
+        ));
+        match case.outcome {
+            JunitOutcome::Passed => {}
+            JunitOutcome::Failed(ref message) => {
+                raw.push_str(&format!(
+                    "      <failure message=\"{}\">{}</failure>\n",
+                    masked(message),
+                    masked(message)
+                ));
+            }
+            JunitOutcome::Skipped(ref message) => {
+                raw.push_str(&format!("      <skipped message=\"{}\"/>\n", masked(message)));
+            }
+        }
+        if let Some(ref output) = case.output {
+            raw.push_str(&format!("      <system-out>{}</system-out>\n", masked(output)));
+        }
+        raw.push_str("    </testcase>\n");
+    }
+    raw.push_str("  </testsuite>\n");
+    return raw;
+}
+
+/// Escape text for use in an XML attribute or element
+fn escape(value: &str) -> String {
+    let mut rtn = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => rtn.push_str("&amp;"),
+            '<' => rtn.push_str("&lt;"),
+            '>' => rtn.push_str("&gt;"),
+            '"' => rtn.push_str("&quot;"),
+            '\'' => rtn.push_str("&apos;"),
+            '\n' => rtn.push_str("&#10;"),
+            c if (c as u32) < 0x20 && c != '\t' && c != '\r' => {}
+            c => rtn.push(c),
+        }
+    }
+    return rtn;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, render_suite, JunitCase, JunitOutcome};
+    use crate::utils::secrets::PupSecrets;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a < b & \"c\""), "a &lt; b &amp; &quot;c&quot;");
+        assert_eq!(escape("one\ntwo\u{1b}"), "one&#10;two");
+    }
+
+    #[test]
+    fn test_secrets_are_masked_before_escaping() {
+        let secrets = PupSecrets::new();
+        assert!(secrets.add_value("p<ss&wo\"rd\\"));
+        let cases = vec![JunitCase {
+            classname: String::from("tests.actions.login"),
+            name: String::from("1.0.0"),
+            seconds: 0.0,
+            outcome: JunitOutcome::Failed(String::from("Bad password: p<ss&wo\"rd\\")),
+            output: Some(String::from("using p<ss&wo\"rd\\\n")),
+        }];
+        let raw = render_suite("tests.actions.login", &cases, &secrets);
+        assert!(!raw.contains("ss&amp;wo"));
+        assert!(raw.contains("<failure message=\"Bad password: ****\">"));
+        assert!(raw.contains("<system-out>using ****&#10;</system-out>"));
+    }
+}
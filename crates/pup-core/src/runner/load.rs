@@ -0,0 +1,32 @@
+use crate::lock::PupLockRoot;
+
+/// The state carried through loading a single root task and all of its steps
+#[derive(Debug, Clone, Default)]
+pub struct PupLoadState {
+    /// The chain of name#version identifiers from the root to the task being loaded
+    pub chain: Vec<String>,
+
+    /// If set, use the locked version for any step reference in the lock
+    pub locked: Option<PupLockRoot>,
+
+    /// The version every step reference resolved to
+    pub resolved: PupLockRoot,
+}
+
+impl PupLoadState {
+    pub fn new(locked: Option<PupLockRoot>) -> PupLoadState {
+        return PupLoadState {
+            chain: Vec::new(),
+            locked,
+            resolved: PupLockRoot::default(),
+        };
+    }
+
+    /// Return the locked version for a step reference, if any
+    pub fn locked_version(&self, reference: &str) -> Option<String> {
+        return match self.locked {
+            Some(ref lock) => lock.version(reference).map(|v| v.to_string()),
+            None => None,
+        };
+    }
+}
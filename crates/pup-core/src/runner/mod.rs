@@ -1,8 +1,22 @@
 mod runner;
 mod action;
 mod exec;
+mod state;
+mod load;
+mod record;
+mod fingerprint;
+mod retry;
+mod events;
+mod junit;
+mod helpers;
 pub mod env;
 
 pub use self::runner::PupRunner;
-pub use self::action::{PupAction, PupExternalAction, PupActionOptions};
-pub use self::exec::{exec, ExecResult, ExecRequest};
\ No newline at end of file
+pub use self::action::{PupAction, PupExternalAction, PupActionOptions, PupStepRole};
+pub use self::exec::{exec, forward_signals_to_workers, ExecResult, ExecRequest};
+pub use self::state::{PupNodeStatus, PupRunState};
+pub use self::load::PupLoadState;
+pub use self::record::{PupRunRecord, RUN_RECORD_NAME};
+pub use self::retry::PupRetryPolicy;
+pub use self::fingerprint::{PupFingerprintSource, FINGERPRINTS_NAME};
+pub use self::events::{PupEvent, PupEventSink, PupEventStep};
\ No newline at end of file
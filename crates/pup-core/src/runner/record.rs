@@ -0,0 +1,107 @@
+use crate::errors::{PupError, PupErrorType};
+use crate::utils::path;
+use serde_yaml;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process;
+use time;
+
+/// The name of the record of the last run, in the process state folder
+pub const RUN_RECORD_NAME: &str = "run.yml";
+
+/// A record of a single run, saved as each action finishes so a failed run can be resumed
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PupRunRecord {
+    /// The unique id of this run
+    pub run_id: String,
+
+    /// The root tasks that were run
+    #[serde(default)]
+    pub tasks: Vec<String>,
+
+    /// The hash of the resolved plan; a run can only be resumed against the same plan
+    #[serde(default)]
+    pub plan: String,
+
+    /// The outcome of every action in the plan, by action key
+    #[serde(default)]
+    pub actions: BTreeMap<String, PupRunRecordAction>,
+}
+
+/// The outcome of a single action in a run
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PupRunRecordAction {
+    /// Did this action finish running?
+    pub completed: bool,
+
+    /// Did it succeed?
+    pub success: bool,
+}
+
+impl PupRunRecord {
+    /// Create a new record for a run, with a fresh run id
+    pub fn new(tasks: Vec<String>, plan: &str) -> PupRunRecord {
+        return PupRunRecord {
+            run_id: new_run_id(),
+            tasks,
+            plan: plan.to_string(),
+            actions: BTreeMap::new(),
+        };
+    }
+
+    pub fn try_from(record_path: &Path) -> Result<Self, PupError> {
+        return Self::read_record(record_path).map_err(|err| {
+            return PupError::with_error(
+                PupErrorType::InvalidRunState,
+                &format!(
+                    "Unable to read run state: {}: {:?}",
+                    path::display(record_path),
+                    err.description()
+                ),
+                err,
+            );
+        });
+    }
+
+    fn read_record(record_path: &Path) -> Result<Self, PupError> {
+        let mut fp = File::open(&record_path)?;
+        let mut raw = String::new();
+        fp.read_to_string(&mut raw)?;
+        return Ok(serde_yaml::from_str(&raw)?);
+    }
+
+    /// Write the record to disk, creating the folder for it if required
+    pub fn save(&self, record_path: &Path) -> Result<(), PupError> {
+        if let Some(parent) = record_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let raw = serde_yaml::to_string(self)?;
+        let mut fp = File::create(record_path)?;
+        fp.write_all(raw.as_bytes())?;
+        fp.write_all(b"\n")?;
+        return Ok(());
+    }
+
+    /// Return the keys of every action that succeeded
+    pub fn succeeded(&self) -> Vec<String> {
+        return self
+            .actions
+            .iter()
+            .filter(|(_, a)| a.completed && a.success)
+            .map(|(k, _)| k.to_string())
+            .collect();
+    }
+}
+
+/// Generate an id for a run from the current time and process id, eg. 20190812-153012-4711
+fn new_run_id() -> String {
+    let timestamp = match time::strftime("%Y%m%d-%H%M%S", &time::now()) {
+        Ok(t) => t,
+        Err(_) => String::from("unknown"),
+    };
+    return format!("{}-{}", timestamp, process::id());
+}
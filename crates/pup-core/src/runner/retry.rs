@@ -0,0 +1,114 @@
+use crate::errors::{PupError, PupErrorType};
+use crate::manifest::PupManifestRetry;
+use crate::utils::duration::parse_duration;
+use std::time::Duration;
+
+/// How often, and when, to retry a worker that fails
+#[derive(Debug, Clone)]
+pub struct PupRetryPolicy {
+    /// The total number of attempts, including the first
+    pub attempts: usize,
+
+    /// How long to wait before the first retry
+    pub delay: Duration,
+
+    /// The delay is multiplied by this after every retry
+    pub backoff: f64,
+
+    /// If not empty, only these exit codes are retried
+    pub exit_codes: Vec<i32>,
+}
+
+impl Default for PupRetryPolicy {
+    fn default() -> Self {
+        return PupRetryPolicy {
+            attempts: 1,
+            delay: Duration::from_secs(0),
+            backoff: 1.0,
+            exit_codes: Vec::new(),
+        };
+    }
+}
+
+impl PupRetryPolicy {
+    pub fn try_from(retry: &PupManifestRetry) -> Result<Self, PupError> {
+        if retry.attempts == 0 || retry.backoff < 1.0 {
+            return Err(PupError::with_message(
+                PupErrorType::InvalidRequest,
+                &format!(
+                    "Invalid retry policy: attempts must be at least 1 and backoff at least 1: {:?}",
+                    retry
+                ),
+            ));
+        }
+        return Ok(PupRetryPolicy {
+            attempts: retry.attempts,
+            delay: match retry.delay.as_str() {
+                "" => Duration::from_secs(0),
+                d => parse_duration(d)?,
+            },
+            backoff: retry.backoff,
+            exit_codes: retry.exit_codes.clone(),
+        });
+    }
+
+    /// Should a failed attempt be retried?
+    /// Timeouts are never retried, and if a list of exit codes is given,
+    /// only a worker that exited with one of them is.
+    pub fn should_retry(&self, err: &PupError, exit_code: Option<i32>) -> bool {
+        if err.error_type == PupErrorType::WorkerTimedOut {
+            return false;
+        }
+        if self.exit_codes.len() == 0 {
+            return true;
+        }
+        return match exit_code {
+            Some(code) => self.exit_codes.contains(&code),
+            None => false,
+        };
+    }
+
+    /// The delay to use after 'delay'
+    pub fn next_delay(&self, delay: Duration) -> Duration {
+        let millis = delay.as_secs() * 1000 + delay.subsec_millis() as u64;
+        return Duration::from_millis((millis as f64 * self.backoff) as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PupRetryPolicy;
+    use crate::errors::{PupError, PupErrorType};
+    use crate::manifest::PupManifestRetry;
+    use std::time::Duration;
+
+    #[test]
+    fn test_retry_policy_backoff() {
+        let policy = PupRetryPolicy::try_from(&PupManifestRetry {
+            attempts: 4,
+            delay: String::from("2s"),
+            backoff: 2.0,
+            exit_codes: Vec::new(),
+        })
+        .unwrap();
+        assert_eq!(policy.delay, Duration::from_secs(2));
+        assert_eq!(policy.next_delay(policy.delay), Duration::from_secs(4));
+        assert_eq!(policy.next_delay(Duration::from_secs(4)), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn test_retry_policy_exit_codes() {
+        let policy = PupRetryPolicy {
+            exit_codes: vec![75],
+            ..PupRetryPolicy::default()
+        };
+        let failed = PupError::from(PupErrorType::WorkerFailed);
+        assert!(policy.should_retry(&failed, Some(75)));
+        assert!(!policy.should_retry(&failed, Some(1)));
+        assert!(!policy.should_retry(&failed, None));
+
+        let any = PupRetryPolicy::default();
+        assert!(any.should_retry(&failed, None));
+        assert!(!any.should_retry(&PupError::from(PupErrorType::WorkerTimedOut), None));
+    }
+}
@@ -1,10 +1,21 @@
 use crate::context::PupContext;
 use crate::errors::{PupError, PupErrorType};
+use crate::history::PupHistoryRecord;
 use crate::logger::get_logger;
+use crate::lock::PupLockRoot;
 use crate::runner::action::PupActionOptions;
-use crate::runner::PupAction;
+use crate::runner::{PupAction, PupStepRole};
+use crate::runner::junit::write_junit;
+use crate::runner::{PupEvent, PupEventStep, PupLoadState, PupRunRecord, PupRunState};
+use crate::utils::hash::hash_lines;
 use crate::utils::path;
+use base_logging::Level;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::path::Path;
+use std::time::Instant;
+use time;
+use time::Tm;
 
 /// A set of tasks to be run
 #[derive(Clone)]
@@ -14,6 +25,19 @@ pub struct PupRunner {
 
     /// The root action to run
     root: PupAction,
+
+    /// The number of places in the plan each distinct action key is used.
+    /// Any key used more than once is a shared node in the DAG.
+    fan_in: HashMap<String, usize>,
+
+    /// The state of the current run
+    state: PupRunState,
+
+    /// The names of the root tasks, in the order they were added
+    names: Vec<String>,
+
+    /// When the last run started and finished
+    times: Option<(Tm, Tm)>,
 }
 
 impl PupRunner {
@@ -22,6 +46,10 @@ impl PupRunner {
         return PupRunner {
             context: context.clone(),
             root: PupAction::new(),
+            fan_in: HashMap::new(),
+            state: PupRunState::new(),
+            names: Vec::new(),
+            times: None,
         };
     }
 
@@ -29,30 +57,186 @@ impl PupRunner {
     /// name should be a standard format name, eg. foo.bar.foobar#1.0.0
     /// or, to just use whatever the latest version is, foo.bar.foobar
     pub fn add(&mut self, name: &str) -> Result<(), PupError> {
+        self.add_locked(name, None)?;
+        return Ok(());
+    }
+
+    /// Add the entire DAG for a runner from a base task, using the versions in
+    /// 'locked' for any step references it contains.
+    /// Returns the version that every step reference resolved to.
+    pub fn add_locked(
+        &mut self,
+        name: &str,
+        locked: Option<PupLockRoot>,
+    ) -> Result<PupLockRoot, PupError> {
+        let mut state = PupLoadState::new(locked);
         let mut action = PupAction::new();
-        action.load(
+        action.load_with_state(
             &self.context,
             name,
             &self.context.global_env,
             &self.context.env,
+            &mut state,
         )?;
+        index_fan_in(&mut self.fan_in, &action);
         self.root.children.push(action);
-        return Ok(());
+        self.names.push(name.to_string());
+        return Ok(state.resolved);
+    }
+
+    /// Return the number of distinct actions in the plan.
+    pub fn node_count(&self) -> usize {
+        return self.fan_in.len();
+    }
+
+    /// Return the number of places in the plan the action with this key is used.
+    pub fn fan_in(&self, key: &str) -> usize {
+        return *self.fan_in.get(key).unwrap_or(&0);
+    }
+
+    /// Return a hash of the resolved plan: every action key, in order, at its depth.
+    /// Any change to the versions, env or shape of the plan changes the hash.
+    pub fn plan_hash(&self) -> String {
+        let mut lines = Vec::new();
+        for child in self.root.children.iter() {
+            plan_lines(child, 0, &mut lines);
+        }
+        return hash_lines(&lines);
+    }
+
+    /// Skip every action that succeeded in a previous run of the same plan.
+    /// Returns the number of actions that will be skipped.
+    pub fn resume(&mut self, record: &PupRunRecord) -> Result<usize, PupError> {
+        if record.tasks != self.names || record.plan != self.plan_hash() {
+            return Err(PupError::with_message(
+                PupErrorType::RunStateMismatch,
+                &format!(
+                    "Unable to resume run {}: the plan for {} has changed since it ran",
+                    record.run_id,
+                    self.names.join(", ")
+                ),
+            ));
+        }
+        let succeeded = record.succeeded();
+        for key in succeeded.iter() {
+            self.state.resume(key);
+        }
+        return Ok(succeeded.len());
+    }
+
+    /// Save a record of the run to 'path', updated as every action finishes,
+    /// so that a failed run can be resumed. Returns the id of the run.
+    pub fn record_to(&mut self, path: &Path) -> Result<String, PupError> {
+        let mut record = PupRunRecord::new(self.names.clone(), &self.plan_hash());
+        for key in self.fan_in.keys() {
+            record.actions.insert(key.to_string(), Default::default());
+        }
+        let run_id = record.run_id.clone();
+        self.state.record_to(path, record)?;
+        return Ok(run_id);
+    }
+
+    /// Skip any action with inputs or outputs that is unchanged since it last
+    /// succeeded, using the fingerprint database at 'path'.
+    pub fn use_fingerprints(&mut self, path: &Path) -> Result<(), PupError> {
+        return self.state.use_fingerprints(path);
     }
 
     /// Actually go and execute all the actions.
     /// The args should be any extra arguments to invoke on all workers, eg. config file.
+    /// Workers run in their own action folders; the current directory is never changed.
     pub fn run(&mut self, options: PupActionOptions) -> Result<(), PupError> {
         if self.root.completed {
             return Err(PupError::from(PupErrorType::RunnerAlreadyCompleted));
         }
 
+        let options = match options.timeout {
+            Some(timeout) => options.with_timeout(timeout, Instant::now()),
+            None => options,
+        };
+
+        let time_start = Instant::now();
+        let started = time::now();
+        options.emit(PupEvent::RunStarted {
+            run_id: self.state.run_id(),
+            tasks: self.names.clone(),
+            dry_run: options.dry_run,
+        });
+        options.emit(PupEvent::PlanResolved { steps: self.plan() });
+
         let mut logger = get_logger();
+        let mut rtn = Ok(());
         for child in self.root.children.iter_mut() {
-            child.run(&mut logger, &options)?;
+            let result = child.run(&mut logger, &options, &self.state);
+            if rtn.is_ok() {
+                rtn = result;
+            }
+            if rtn.is_err() && !options.keep_going {
+                break;
+            }
         }
 
-        return Ok(());
+        // Summarize every failure, not just the first
+        let failures = self.failures();
+        if failures.len() > 0 {
+            logger.log(Level::Info, format!("Failed steps:"));
+            for failure in failures.iter() {
+                logger.log(Level::Info, format!("-- {}", failure));
+            }
+        }
+        if rtn.is_err() && options.keep_going {
+            let failed: Vec<&String> = failures.iter().filter(|f| !f.ends_with(IGNORED)).collect();
+            rtn = Err(PupError::with_message(
+                PupErrorType::StepsFailed,
+                &format!(
+                    "{} steps failed: {}",
+                    failed.len(),
+                    failed.iter().map(|f| f.as_str()).collect::<Vec<&str>>().join("; ")
+                ),
+            ));
+        }
+
+        self.root.completed = true;
+        self.root.success = rtn.is_ok();
+        self.times = Some((started, time::now()));
+        options.emit(PupEvent::RunFinished {
+            success: self.root.success,
+            duration_ms: time_start.elapsed().as_millis() as i64,
+            failures,
+        });
+        return rtn;
+    }
+
+    /// Write a JUnit XML report of the last run to 'path', with a testcase for every step.
+    /// Steps that were skipped or never reached are marked as skipped.
+    pub fn write_junit(&self, path: &Path) -> Result<(), PupError> {
+        return write_junit(path, &self.names, &self.root.children);
+    }
+
+    /// Return a record of the last run for the run history, if there has been a run
+    pub fn history_record(&self, run_id: &str) -> Option<PupHistoryRecord> {
+        return self.times.map(|(started, finished)| {
+            PupHistoryRecord::from_run(run_id, &self.names, &self.root.children, started, finished)
+        });
+    }
+
+    /// Return every step in the plan, in the order it is visited
+    pub fn plan(&self) -> Vec<PupEventStep> {
+        let mut steps = Vec::new();
+        for child in self.root.children.iter() {
+            plan_steps(child, 1, &mut steps);
+        }
+        return steps;
+    }
+
+    /// Return a description of every step whose worker failed in the last run,
+    /// including those ignored because they continue on failure.
+    pub fn failures(&self) -> Vec<String> {
+        let mut rtn = Vec::new();
+        for child in self.root.children.iter() {
+            collect_failures(child, false, &mut rtn);
+        }
+        return rtn;
     }
 
     /// Return a copy of the internal action; for cloning, testing, etc.
@@ -61,24 +245,88 @@ impl PupRunner {
     }
 }
 
+const IGNORED: &str = " (ignored)";
+
+fn collect_failures(action: &PupAction, ignored: bool, failures: &mut Vec<String>) {
+    if let Some(ref err) = action.error {
+        let suffix = if ignored { IGNORED } else { "" };
+        failures.push(format!("{}: {}{}", action.name(), err, suffix));
+    }
+    for child in action.all_children() {
+        let child_ignored = ignored || (child.continues_on_failure() && !child.success);
+        collect_failures(child, child_ignored, failures);
+    }
+}
+
+fn plan_steps(action: &PupAction, depth: usize, steps: &mut Vec<PupEventStep>) {
+    steps.push(PupEventStep {
+        step: action.name(),
+        depth,
+        role: action.role,
+    });
+    for child in action.all_children() {
+        plan_steps(child, depth + 1, steps);
+    }
+}
+
+fn plan_lines(action: &PupAction, depth: usize, lines: &mut Vec<String>) {
+    lines.push(format!("{} {:?} {}", depth, action.role, action.key));
+    for child in action.all_children() {
+        plan_lines(child, depth + 1, lines);
+    }
+}
+
+fn index_fan_in(fan_in: &mut HashMap<String, usize>, action: &PupAction) {
+    let count = fan_in.entry(action.key.clone()).or_insert(0);
+    *count += 1;
+
+    // The children of a shared node are only counted the first time
+    if *count == 1 {
+        for child in action.all_children() {
+            index_fan_in(fan_in, child);
+        }
+    }
+}
+
 impl fmt::Debug for PupRunner {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref profile) = self.context.profile {
+            let _ = writeln!(f, " Profile: {}", profile);
+        }
+        let mut seen = HashSet::new();
         let count = self.root.children.len();
         for i in 0..count {
             let child = &self.root.children[i];
-            debug_print(f, child, 1, i == count - 1);
+            debug_print(f, child, &self.fan_in, &mut seen, 1, i == count - 1);
         }
         write!(f, "")
     }
 }
 
-fn debug_print(f: &mut fmt::Formatter, action: &PupAction, offset: usize, is_last: bool) {
+fn debug_print(
+    f: &mut fmt::Formatter,
+    action: &PupAction,
+    fan_in: &HashMap<String, usize>,
+    seen: &mut HashSet<String>,
+    offset: usize,
+    is_last: bool,
+) {
     let ext = action.external.as_ref().unwrap();
+    let children = action.all_children();
 
     // Name
     let _ = write!(f, " ");
     let _ = write!(f, "{}", "-".repeat(offset));
     let _ = write!(f, " {} #{}", ext.task.name, ext.version.version);
+    match action.role {
+        PupStepRole::Step => {}
+        PupStepRole::Undo => {
+            let _ = write!(f, " (undo)");
+        }
+        PupStepRole::Finally => {
+            let _ = write!(f, " (finally)");
+        }
+    }
 
     // Action
     let _ = write!(
@@ -87,22 +335,47 @@ fn debug_print(f: &mut fmt::Formatter, action: &PupAction, offset: usize, is_las
         ext.worker.name,
         path::display(&ext.version.path)
     );
-    if !is_last || action.children.len() > 0 {
+
+    // Shared nodes only expand the first time they appear
+    let shared = *fan_in.get(&action.key).unwrap_or(&1);
+    let expand = seen.insert(action.key.clone());
+    if shared > 1 {
+        if expand {
+            let _ = write!(f, " (shared, fan-in {})", shared);
+        } else {
+            let _ = write!(f, " (shared, fan-in {}, see above)", shared);
+        }
+    }
+    if !is_last || (expand && children.len() > 0) {
         let _ = write!(f, "\n");
     }
+    if !expand {
+        return;
+    }
 
-    let count = action.children.len();
+    let count = children.len();
     for i in 0..count {
-        let child = &action.children[i];
-        debug_print(f, child, offset + 1, is_last && i == count - 1);
+        debug_print(f, children[i], fan_in, seen, offset + 1, is_last && i == count - 1);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::PupRunner;
+    use crate::errors::PupErrorType;
+    use crate::history::PupStepOutcome;
     use crate::runner::action::PupActionOptions;
+    use crate::runner::fingerprint::PupFingerprints;
+    use crate::runner::{PupEventSink, PupRunRecord};
+    use serde_json;
+    use crate::process::PupProcess;
+    use crate::testing::copy_folder;
+    use crate::testing::test_context_folder;
     use crate::testing::test_fixture;
+    use crate::testing::test_output_path;
+    use std::env;
+    use std::fs;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn load_runner_from_working_task() {
@@ -119,6 +392,37 @@ mod tests {
         assert!(runner.add("tests.builds.bad").is_err());
     }
 
+    #[test]
+    fn load_runner_fails_on_recursive_task() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        let err = runner.add("tests.cycles.self").err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::RecursiveTask);
+        assert!(err
+            .error_detail
+            .contains("tests.cycles.self#0.0.1 -> tests.cycles.self#0.0.1"));
+    }
+
+    #[test]
+    fn load_runner_fails_on_indirect_recursive_task() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        let err = runner.add("tests.cycles.first").err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::RecursiveTask);
+        assert!(err.error_detail.contains(
+            "tests.cycles.first#0.0.1 -> tests.cycles.second#0.0.1 -> tests.cycles.first#0.0.1"
+        ));
+    }
+
+    #[test]
+    fn load_runner_fails_past_max_depth() {
+        let mut process = test_fixture();
+        process.context.max_depth = 2;
+        let mut runner = PupRunner::new(&process.context);
+        let err = runner.add("tests.actions.nested").err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::MaxDepthExceeded);
+    }
+
     #[test]
     fn run_runner_in_dry_run_mode() {
         let process = test_fixture();
@@ -131,6 +435,7 @@ mod tests {
                     .iter()
                     .map(|x| String::from(*x))
                     .collect(),
+                ..PupActionOptions::default()
             })
             .is_ok());
     }
@@ -147,8 +452,642 @@ mod tests {
                     .iter()
                     .map(|x| String::from(*x))
                     .collect(),
+                ..PupActionOptions::default()
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn load_runner_shares_identical_steps() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.builds.shared").is_ok());
+
+        // prepareFolder is used directly, and via setVersion, with the same env
+        let root = &runner.root.children[0];
+        let direct = &root.children[1];
+        let nested = &root.children[0].children[0];
+        assert_eq!(direct.key, nested.key);
+        assert_eq!(runner.fan_in(&direct.key), 2);
+
+        // The same step with a different env is a different node
+        let overridden = &root.children[2];
+        assert_ne!(direct.key, overridden.key);
+        assert_eq!(runner.fan_in(&overridden.key), 1);
+
+        // shared, setVersion, and two variants of prepareFolder with two children each
+        assert_eq!(runner.node_count(), 8);
+        println!("{:?}", runner);
+    }
+
+    #[test]
+    fn run_runner_runs_shared_steps_once() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.builds.shared").is_ok());
+        assert!(runner
+            .run(PupActionOptions {
+                dry_run: true,
+                ..PupActionOptions::default()
+            })
+            .is_ok());
+        assert_eq!(runner.state.completed_count(), runner.node_count());
+    }
+
+    #[test]
+    fn load_runner_keeps_steps_with_different_settings_apart() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.builds.settings").is_ok());
+
+        // The same task and env, with a different timeout or failure handling, are different nodes
+        let root = &runner.root.children[0];
+        assert_ne!(root.children[0].key, root.children[1].key);
+        assert_ne!(root.children[0].key, root.children[2].key);
+        assert_ne!(root.children[1].key, root.children[2].key);
+        assert_eq!(root.children[2].key, root.children[3].key);
+        assert_eq!(runner.fan_in(&root.children[2].key), 2);
+    }
+
+    #[test]
+    fn run_runner_runs_independent_steps_in_parallel() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.builds.parallel").is_ok());
+        assert!(runner
+            .run(PupActionOptions {
+                dry_run: true,
+                jobs: 2,
+                ..PupActionOptions::default()
+            })
+            .is_ok());
+
+        // Children are kept in their original order
+        let root = &runner.root.children[0];
+        let names: Vec<String> = root
+            .children
+            .iter()
+            .map(|c| c.external.as_ref().unwrap().task.name.clone())
+            .collect();
+        assert_eq!(
+            names,
+            vec!(
+                "tests.actions.setVersion",
+                "tests.actions.runBuild",
+                "tests.actions.prepareFolder",
+                "tests.common.prepFolder"
+            )
+        );
+        assert_eq!(runner.state.completed_count(), runner.node_count());
+    }
+
+    #[test]
+    fn run_runner_stops_parallel_steps_on_failure() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.builds.parallel").is_ok());
+        assert!(runner
+            .run(PupActionOptions {
+                jobs: 2,
+                ..PupActionOptions::default()
+            })
+            .is_err());
+        assert_eq!(runner.root.children[0].children.len(), 4);
+    }
+
+    #[test]
+    fn run_runner_does_not_change_current_dir() {
+        let process = test_fixture();
+        let before = env::current_dir().unwrap();
+
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.actions.nested").is_ok());
+        assert!(runner
+            .run(PupActionOptions {
+                dry_run: true,
+                ..PupActionOptions::default()
+            })
+            .is_ok());
+        assert_eq!(env::current_dir().unwrap(), before);
+
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.actions.nested").is_ok());
+        assert!(runner.run(PupActionOptions::default()).is_err());
+        assert_eq!(env::current_dir().unwrap(), before);
+    }
+
+    #[test]
+    fn run_runner_records_run_state() {
+        let process = test_fixture();
+        let record_path = test_output_path("record.yml");
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.builds.deployment").is_ok());
+        let run_id = runner.record_to(&record_path).unwrap();
+        assert!(runner
+            .run(PupActionOptions {
+                dry_run: true,
+                ..PupActionOptions::default()
+            })
+            .is_ok());
+
+        let root = &runner.root.children[0];
+        assert!(root.completed && root.success);
+        assert!(root.children[0].completed && root.children[0].success);
+
+        let record = PupRunRecord::try_from(&record_path).unwrap();
+        assert_eq!(record.run_id, run_id);
+        assert_eq!(record.plan, runner.plan_hash());
+        assert_eq!(record.actions.len(), runner.node_count());
+        assert_eq!(record.succeeded().len(), runner.node_count());
+    }
+
+    #[test]
+    fn run_runner_resumes_from_failed_step() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.builds.deployment").is_ok());
+        let root_key = runner.root.children[0].key.clone();
+
+        // A previous run where everything but the root task succeeded
+        let mut record = PupRunRecord::new(runner.names.clone(), &runner.plan_hash());
+        for key in runner.fan_in.keys() {
+            record.actions.entry(key.to_string()).or_default().completed = true;
+            record.actions.get_mut(key).unwrap().success = key != &root_key;
+        }
+
+        assert_eq!(runner.resume(&record).unwrap(), runner.node_count() - 1);
+        assert!(runner
+            .run(PupActionOptions {
+                dry_run: true,
+                ..PupActionOptions::default()
+            })
+            .is_ok());
+        assert_eq!(runner.state.completed_count(), 1);
+        assert!(runner.root.children[0].children[0].success);
+    }
+
+    #[test]
+    fn resume_fails_if_plan_changed() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.builds.deployment").is_ok());
+        let record = PupRunRecord::new(runner.names.clone(), &runner.plan_hash());
+
+        let mut other = PupRunner::new(&process.context);
+        assert!(other.add("tests.builds.shared").is_ok());
+        let err = other.resume(&record).err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::RunStateMismatch);
+
+        let mut changed = process.context.clone();
+        changed.env.insert(String::from("FOO"), String::from("changed"));
+        let mut other = PupRunner::new(&changed);
+        assert!(other.add("tests.builds.deployment").is_ok());
+        let err = other.resume(&record).err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::RunStateMismatch);
+    }
+
+    #[test]
+    fn run_runner_skips_up_to_date_steps() {
+        let db_path = test_output_path("fingerprints.yml");
+        let _ = fs::remove_file(&db_path);
+
+        // Run a copy of the task, so its output isn't written to the sample folder
+        let fixture = test_output_path("incremental");
+        let _ = fs::remove_dir_all(&fixture);
+        copy_folder(
+            &test_context_folder().join("tasks/tests/incremental"),
+            &fixture.join("tasks/tests/incremental"),
+        );
+        let manifest = format!(
+            "tasks_path: tasks\nworkers_path: {:?}\ntasks: []\nenvironment:\n  foo: bar\n",
+            test_context_folder().join("bin")
+        );
+        fs::write(fixture.join("pup.yml"), manifest).unwrap();
+        let process = PupProcess::load_from(fixture.join("pup.yml"), None).unwrap();
+
+        // The worker never runs successfully, so write its output here
+        let output_path = fixture.join("tasks/tests/incremental/generate/versions/1.0.0/output");
+        fs::create_dir_all(&output_path).unwrap();
+        fs::write(output_path.join("bar.txt"), "generated from input/source.txt\n").unwrap();
+
+        // Never run, so the (invalid) worker runs and fails
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.incremental.generate").is_ok());
+        runner.use_fingerprints(&db_path).unwrap();
+        assert!(runner.run(PupActionOptions::default()).is_err());
+
+        // Once the last successful run has the same fingerprint, the worker is skipped
+        let action = &runner.root.children[0];
+        assert_eq!(action.external.as_ref().unwrap().outputs, vec!("output/bar.txt"));
+        let mut db = PupFingerprints::default();
+        db.actions
+            .insert(action.key.clone(), action.fingerprint().unwrap().unwrap());
+        db.save(&db_path).unwrap();
+
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.incremental.generate").is_ok());
+        runner.use_fingerprints(&db_path).unwrap();
+        assert!(runner.run(PupActionOptions::default()).is_ok());
+
+        // Without fingerprints, it always runs
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.incremental.generate").is_ok());
+        assert!(runner.run(PupActionOptions::default()).is_err());
+    }
+
+    #[test]
+    fn load_runner_fails_on_undefined_variables_in_strict_mode() {
+        let mut process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.templates.typo#0.0.1").is_ok());
+
+        process.context.strict = true;
+        let mut runner = PupRunner::new(&process.context);
+        let err = runner.add("tests.templates.typo#0.0.1").err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::UndefinedVariable);
+        assert!(err.error_detail.contains("Step tests.actions.runBuild#1.0.0 in tests.templates.typo#0.0.1"));
+        assert!(err.error_detail.contains("skip: Undefined variable SKP_STEP in template '{{SKP_STEP}}'"));
+
+        let mut runner = PupRunner::new(&process.context);
+        let err = runner.add("tests.templates.typo#0.0.2").err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::UndefinedVariable);
+        assert!(err.error_detail.contains("environment flavour: Undefined variable FLAVOR"));
+    }
+
+    #[test]
+    fn load_runner_reads_step_timeouts() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.timeouts.limited").is_ok());
+        assert_eq!(
+            runner.root.children[0].children[0].timeout,
+            Some(Duration::from_secs(600))
+        );
+
+        let mut runner = PupRunner::new(&process.context);
+        let err = runner.add("tests.timeouts.invalid").err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::InvalidDuration);
+    }
+
+    #[test]
+    fn run_runner_fails_once_run_times_out() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.timeouts.limited").is_ok());
+        let err = runner
+            .run(PupActionOptions {
+                timeout: Some(Duration::from_millis(0)),
+                ..PupActionOptions::default()
+            })
+            .err()
+            .unwrap();
+        assert_eq!(err.error_type, PupErrorType::WorkerTimedOut);
+    }
+
+    #[test]
+    fn run_runner_retries_failed_steps() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.retries.flaky#0.0.1").is_ok());
+        assert!(runner.run(PupActionOptions::default()).is_err());
+        assert_eq!(runner.root.children[0].children[0].attempts, 3);
+
+        // Only the listed exit codes are retried
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.retries.flaky#0.0.2").is_ok());
+        assert!(runner.run(PupActionOptions::default()).is_err());
+        assert_eq!(runner.root.children[0].children[0].attempts, 1);
+    }
+
+    #[test]
+    fn run_runner_stops_retrying_at_the_deadline() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.retries.flaky#0.0.3").is_ok());
+        let started = Instant::now();
+        let err = runner
+            .run(PupActionOptions {
+                timeout: Some(Duration::from_millis(500)),
+                ..PupActionOptions::default()
+            })
+            .err()
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(err.error_type, PupErrorType::WorkerTimedOut);
+        assert_eq!(runner.root.children[0].children[0].attempts, 2);
+    }
+
+    #[test]
+    fn run_runner_stops_on_first_failure() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.failures.mixed#0.0.1").is_ok());
+        let err = runner.run(PupActionOptions::default()).err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::WorkerFailed);
+
+        let root = &runner.root.children[0];
+        assert_eq!(root.children[0].attempts, 1);
+        assert_eq!(root.children[1].attempts, 0);
+        assert_eq!(runner.failures().len(), 1);
+    }
+
+    #[test]
+    fn run_runner_writes_worker_logs() {
+        let process = test_fixture();
+        let log_folder = test_output_path("logs");
+        let _ = fs::remove_dir_all(&log_folder);
+
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.failures.mixed#0.0.1").is_ok());
+        let err = runner
+            .run(PupActionOptions {
+                log_folder: Some(log_folder.clone()),
+                ..PupActionOptions::default()
+            })
+            .err()
+            .unwrap();
+
+        let log_path = err.log_path.unwrap();
+        assert!(log_path.starts_with(&log_folder));
+        assert!(log_path.ends_with("001-tests.actions.runBuild#1.0.0.log"));
+        assert!(log_path.exists());
+        assert!(runner.failures()[0].contains("log:"));
+    }
+
+    #[test]
+    fn run_runner_emits_events() {
+        let process = test_fixture();
+        let events_path = test_output_path("events.json");
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.builds.deployment#0.0.2-skip").is_ok());
+        assert!(runner.add("tests.builds.deployment#0.0.2-if").is_ok());
+        assert!(runner
+            .run(PupActionOptions {
+                dry_run: true,
+                events: Some(PupEventSink::open(events_path.to_str().unwrap()).unwrap()),
+                ..PupActionOptions::default()
+            })
+            .is_ok());
+
+        let raw = fs::read_to_string(&events_path).unwrap();
+        let events: Vec<serde_json::Value> = raw
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let names: Vec<&str> = events.iter().map(|e| e["event"].as_str().unwrap()).collect();
+        assert_eq!(names[0], "run_started");
+        assert_eq!(names[1], "plan_resolved");
+        assert_eq!(names[names.len() - 1], "run_finished");
+        assert_eq!(events[names.len() - 1]["success"], true);
+        assert!(events.iter().all(|e| e["time"].is_string()));
+
+        // Shared steps are only entered once; later visits skip them, and their children
+        let entered = names.iter().filter(|n| **n == "step_entered").count();
+        let finished = names.iter().filter(|n| **n == "step_finished").count();
+        let skipped: Vec<&serde_json::Value> =
+            events.iter().filter(|e| e["event"] == "step_skipped").collect();
+        let shared = skipped.iter().filter(|e| e["reason"] == "completed").count();
+        assert_eq!(entered, finished);
+        assert!(shared > 0);
+        let plan = events[1]["steps"].as_array().unwrap();
+        assert_eq!(plan.iter().filter(|s| s["depth"] == 1).count(), 2);
+        assert!(entered + shared <= plan.len());
+        assert!(skipped.iter().any(|e| e["reason"] == "skip"));
+        assert!(skipped.iter().any(|e| e["reason"] == "if"));
+    }
+
+    #[test]
+    fn run_runner_writes_junit_report() {
+        let process = test_fixture();
+        let log_folder = test_output_path("junit-logs");
+        let report_path = test_output_path("junit.xml");
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.failures.mixed#0.0.1").is_ok());
+        assert!(runner
+            .run(PupActionOptions {
+                log_folder: Some(log_folder),
+                ..PupActionOptions::default()
             })
             .is_err());
+        assert!(runner.write_junit(&report_path).is_ok());
+
+        let raw = fs::read_to_string(&report_path).unwrap();
+        assert!(raw.contains(
+            "<testsuite name=\"tests.failures.mixed#0.0.1\" tests=\"3\" failures=\"2\" skipped=\"1\""
+        ));
+        assert!(raw.contains("<testcase classname=\"tests.actions.runBuild\" name=\"1.0.0\""));
+        assert!(raw.contains("<failure message=\"WorkerFailed: "));
+        assert!(raw.contains("<skipped message=\"not run\"/>"));
+        assert!(raw.contains("<system-out>"));
+    }
+
+    #[test]
+    fn run_runner_creates_history_record() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.failures.mixed#0.0.1").is_ok());
+        assert!(runner.history_record("run").is_none());
+        assert!(runner.run(PupActionOptions::default()).is_err());
+
+        let record = runner.history_record("run").unwrap();
+        assert!(!record.success);
+        assert_eq!(record.tasks, vec!("tests.failures.mixed#0.0.1"));
+        assert_eq!(record.failed_step.unwrap(), "tests.actions.runBuild#1.0.0");
+        assert!(record.versions.contains(&String::from("tests.actions.runBuild#2.0.0")));
+
+        let outcomes: Vec<PupStepOutcome> = record.steps.iter().map(|s| s.outcome).collect();
+        assert_eq!(
+            outcomes,
+            vec!(PupStepOutcome::Failed, PupStepOutcome::Failed, PupStepOutcome::NotRun)
+        );
+    }
+
+    #[test]
+    fn run_runner_keeps_going_after_failure() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.failures.mixed#0.0.1").is_ok());
+        let err = runner
+            .run(PupActionOptions {
+                keep_going: true,
+                ..PupActionOptions::default()
+            })
+            .err()
+            .unwrap();
+        assert_eq!(err.error_type, PupErrorType::StepsFailed);
+        assert!(err.error_detail.contains("2 steps failed"));
+
+        // Both steps ran, but the task that depends on them is blocked
+        let root = &runner.root.children[0];
+        assert_eq!(root.children[0].attempts, 1);
+        assert_eq!(root.children[1].attempts, 1);
+        assert_eq!(root.attempts, 0);
+        assert!(root.completed && !root.success);
+        assert_eq!(runner.failures().len(), 2);
+    }
+
+    #[test]
+    fn run_runner_reports_every_copy_of_a_failed_shared_step() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.failures.shared").is_ok());
+        let err = runner
+            .run(PupActionOptions {
+                keep_going: true,
+                ..PupActionOptions::default()
+            })
+            .err()
+            .unwrap();
+        assert!(err.error_detail.contains("3 steps failed"));
+
+        let failures = runner.failures();
+        assert_eq!(failures.len(), 3);
+        assert!(failures[2].starts_with("tests.actions.runBuild#2.0.0"));
+        assert!(failures[2].contains("Shared task failed"));
+    }
+
+    #[test]
+    fn run_runner_continues_on_failure() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.failures.mixed#0.0.2").is_ok());
+        assert!(runner.run(PupActionOptions::default()).is_err());
+
+        // The failed step is ignored, so the task's own (invalid) worker runs
+        let root = &runner.root.children[0];
+        assert!(!root.children[0].success);
+        assert_eq!(root.attempts, 1);
+
+        let failures = runner.failures();
+        assert_eq!(failures.len(), 2);
+        assert!(failures[1].starts_with("tests.actions.runBuild#1.0.0"));
+        assert!(failures[1].ends_with("(ignored)"));
+    }
+
+    #[test]
+    fn load_runner_includes_undo_and_finally_steps() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.cleanup.service").is_ok());
+
+        let root = &runner.root.children[0];
+        assert_eq!(root.children.len(), 2);
+        assert_eq!(root.children[0].undo.len(), 1);
+        assert_eq!(root.finally.len(), 1);
+
+        let plan = format!("{:?}", runner);
+        assert!(plan.contains("tests.actions.runBuild #2.0.0 (undo)"));
+        assert!(plan.contains("tests.actions.prepareFolder #1.0.0 (finally)"));
+    }
+
+    #[test]
+    fn run_runner_runs_finally_but_not_undo_on_success() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.cleanup.service").is_ok());
+        assert!(runner
+            .run(PupActionOptions {
+                dry_run: true,
+                ..PupActionOptions::default()
+            })
+            .is_ok());
+
+        let root = &runner.root.children[0];
+        assert!(root.finally[0].completed);
+        assert!(!root.children[0].undo[0].completed);
+    }
+
+    #[test]
+    fn run_runner_undoes_steps_when_a_later_step_fails() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.cleanup.service").is_ok());
+
+        // The first step succeeds, then the second fails
+        let first = runner.root.children[0].children[0].key.clone();
+        runner.state.resume(&first);
+        assert!(runner.run(PupActionOptions::default()).is_err());
+
+        let root = &runner.root.children[0];
+        assert!(root.children[0].success);
+        assert!(!root.children[1].success);
+        assert_eq!(root.children[0].undo[0].attempts, 1);
+        assert!(root.finally[0].completed);
+        assert_eq!(root.attempts, 0);
+    }
+
+    #[test]
+    fn run_runner_undoes_steps_when_its_own_worker_fails() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.cleanup.worker").is_ok());
+
+        // The skipped undo step stays with the step it undoes
+        let root = &runner.root.children[0];
+        assert_eq!(root.skipped.len(), 0);
+        assert_eq!(root.children[0].undo.len(), 1);
+        assert_eq!(root.children[0].undo_skipped.len(), 1);
+        assert_eq!(root.children[0].undo_skipped[0].step, "tests.actions.runBuild#1.0.0");
+
+        // The step succeeds, then the worker of the task fails
+        let first = runner.root.children[0].children[0].key.clone();
+        runner.state.resume(&first);
+        assert!(runner.run(PupActionOptions::default()).is_err());
+
+        let root = &runner.root.children[0];
+        assert!(root.children[0].success);
+        assert_eq!(root.attempts, 1);
+        assert!(root.children[0].undone);
+        assert_eq!(root.children[0].undo[0].attempts, 1);
+    }
+
+    #[test]
+    fn run_runner_leaves_shared_copies_to_be_undone_where_they_ran() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.cleanup.copies").is_ok());
+
+        // Another reference to the first step already ran it in this run
+        let first = runner.root.children[0].children[0].key.clone();
+        assert!(runner.state.claim(&first).is_none());
+        runner.state.finish(&first, true).unwrap();
+        assert!(runner.run(PupActionOptions::default()).is_err());
+
+        let root = &runner.root.children[0];
+        assert!(root.children[0].success);
+        assert!(root.children[0].shared_copy);
+        assert!(!root.children[1].success);
+        assert!(!root.children[0].undo[0].completed);
+    }
+
+    #[test]
+    fn run_runner_runs_finally_steps_that_share_a_key() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.cleanup.shared").is_ok());
+        let key = runner.root.children[0].children[0].key.clone();
+        assert_eq!(runner.root.children[0].finally[0].key, key);
+
+        // The same node already ran earlier in this run
+        assert!(runner
+            .run(PupActionOptions {
+                dry_run: true,
+                ..PupActionOptions::default()
+            })
+            .is_ok());
+        let root = &runner.root.children[0];
+        assert!(root.finally[0].completed);
+        assert!(root.finally[0].skip_reason.is_none());
+
+        // The same node succeeded in a previous run
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.cleanup.shared").is_ok());
+        runner.state.resume(&key);
+        assert!(runner.run(PupActionOptions::default()).is_err());
+        let root = &runner.root.children[0];
+        assert!(root.children[0].skip_reason.is_some());
+        assert!(root.finally[0].skip_reason.is_none());
+        assert_eq!(root.finally[0].attempts, 1);
     }
 
     #[test]
@@ -167,4 +1106,21 @@ mod tests {
         assert_eq!(external.env["PREP_FOLDER_PATH"], "bar/foobar/nested");
         assert_eq!(external.env["PREP_FOLDER_RULE"], "clean");
     }
+
+    #[test]
+    fn test_step_env_file_values() {
+        let process = test_fixture();
+        let mut runner = PupRunner::new(&process.context);
+        assert!(runner.add("tests.envfiles.layered#0.0.1").is_ok());
+
+        let external = runner.root.children[0].children[0].external.take().unwrap();
+        assert_eq!(external.env["BUILD_ENV_FILE"], "loaded");
+        assert_eq!(external.env["BUILD_TARGET"], "bar-build");
+        assert_eq!(external.env["target"], "debug");
+
+        let mut runner = PupRunner::new(&process.context);
+        let err = runner.add("tests.envfiles.layered#0.0.2").err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::InvalidEnvFile);
+        assert!(err.error_detail.contains("Step tests.actions.runBuild#1.0.0 in tests.envfiles.layered#0.0.2"));
+    }
 }
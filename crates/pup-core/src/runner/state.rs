@@ -0,0 +1,177 @@
+use crate::errors::PupError;
+use crate::runner::fingerprint::PupFingerprints;
+use crate::runner::record::{PupRunRecord, PupRunRecordAction};
+use crate::utils::path::exists;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// The mutable state shared by every action during a single run.
+/// Cloning a state returns a handle to the same underlying run.
+#[derive(Clone, Debug)]
+pub struct PupRunState {
+    shared: Arc<(Mutex<PupRunNodes>, Condvar)>,
+}
+
+/// The status of a single action key in a run
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PupNodeStatus {
+    Running,
+    Completed,
+    Failed,
+
+    /// Succeeded in a previous run that this run resumes
+    Resumed,
+}
+
+#[derive(Debug, Default)]
+struct PupRunNodes {
+    /// The status of every action key claimed so far
+    status: HashMap<String, PupNodeStatus>,
+
+    /// If set, the record of this run and the path to save it to when an action finishes
+    record: Option<(PathBuf, PupRunRecord)>,
+
+    /// If set, the fingerprint database and the path to save it to
+    fingerprints: Option<(PathBuf, PupFingerprints)>,
+
+    /// The number of worker logs started so far
+    logs: usize,
+}
+
+impl PupRunState {
+    pub fn new() -> PupRunState {
+        return PupRunState {
+            shared: Arc::new((Mutex::new(PupRunNodes::default()), Condvar::new())),
+        };
+    }
+
+    /// Claim the action with this key to run it.
+    /// Returns None if the caller should run it, or the final status if it has
+    /// already run. If it is running on another thread, wait for it to finish.
+    pub fn claim(&self, key: &str) -> Option<PupNodeStatus> {
+        let &(ref lock, ref changed) = &*self.shared;
+        let mut nodes = lock.lock().unwrap();
+        loop {
+            match nodes.status.get(key) {
+                Some(PupNodeStatus::Running) => {}
+                Some(status) => return Some(*status),
+                None => {
+                    nodes.status.insert(key.to_string(), PupNodeStatus::Running);
+                    return None;
+                }
+            }
+            nodes = changed.wait(nodes).unwrap();
+        }
+    }
+
+    /// Record the outcome of an action claimed with `claim`.
+    /// If the run is being recorded, the record is saved before this returns.
+    pub fn finish(&self, key: &str, success: bool) -> Result<(), PupError> {
+        let &(ref lock, ref changed) = &*self.shared;
+        let status = match success {
+            true => PupNodeStatus::Completed,
+            false => PupNodeStatus::Failed,
+        };
+        let mut nodes = lock.lock().unwrap();
+        nodes.status.insert(key.to_string(), status);
+        changed.notify_all();
+        if let Some((ref path, ref mut record)) = nodes.record {
+            record.actions.insert(
+                key.to_string(),
+                PupRunRecordAction {
+                    completed: true,
+                    success,
+                },
+            );
+            record.save(path)?;
+        }
+        return Ok(());
+    }
+
+    /// Mark an action as having succeeded in a previous run, so it is not run again.
+    pub fn resume(&self, key: &str) {
+        let &(ref lock, _) = &*self.shared;
+        let mut nodes = lock.lock().unwrap();
+        nodes.status.insert(key.to_string(), PupNodeStatus::Resumed);
+    }
+
+    /// Save the record of this run to 'path' now, and again every time an action finishes.
+    pub fn record_to(&self, path: &Path, mut record: PupRunRecord) -> Result<(), PupError> {
+        let &(ref lock, _) = &*self.shared;
+        let mut nodes = lock.lock().unwrap();
+        for (key, action) in record.actions.iter_mut() {
+            action.success = match nodes.status.get(key) {
+                Some(PupNodeStatus::Completed) | Some(PupNodeStatus::Resumed) => true,
+                _ => false,
+            };
+            action.completed = action.success;
+        }
+        record.save(path)?;
+        nodes.record = Some((PathBuf::from(path), record));
+        return Ok(());
+    }
+
+    /// Use the fingerprint database at 'path' to skip actions that are up to date.
+    pub fn use_fingerprints(&self, path: &Path) -> Result<(), PupError> {
+        let db = match exists(path) {
+            true => PupFingerprints::try_from(path)?,
+            false => PupFingerprints::default(),
+        };
+        let &(ref lock, _) = &*self.shared;
+        lock.lock().unwrap().fingerprints = Some((PathBuf::from(path), db));
+        return Ok(());
+    }
+
+    /// Is this run using a fingerprint database?
+    pub fn has_fingerprints(&self) -> bool {
+        let &(ref lock, _) = &*self.shared;
+        return lock.lock().unwrap().fingerprints.is_some();
+    }
+
+    /// Did the action with this key last succeed with the same fingerprint?
+    pub fn is_up_to_date(&self, key: &str, fingerprint: &str) -> bool {
+        let &(ref lock, _) = &*self.shared;
+        return match lock.lock().unwrap().fingerprints {
+            Some((_, ref db)) => db.actions.get(key).map(|f| f == fingerprint).unwrap_or(false),
+            None => false,
+        };
+    }
+
+    /// Save the fingerprint of an action that succeeded.
+    pub fn save_fingerprint(&self, key: &str, fingerprint: &str) -> Result<(), PupError> {
+        let &(ref lock, _) = &*self.shared;
+        let mut nodes = lock.lock().unwrap();
+        if let Some((ref path, ref mut db)) = nodes.fingerprints {
+            db.actions.insert(key.to_string(), fingerprint.to_string());
+            db.save(path)?;
+        }
+        return Ok(());
+    }
+
+    /// The number of the next worker log in this run, from 1, so every log has its own file.
+    pub fn next_log_number(&self) -> usize {
+        let &(ref lock, _) = &*self.shared;
+        let mut nodes = lock.lock().unwrap();
+        nodes.logs += 1;
+        return nodes.logs;
+    }
+
+    /// The id of the run being recorded, if any.
+    pub fn run_id(&self) -> Option<String> {
+        let &(ref lock, _) = &*self.shared;
+        return lock.lock().unwrap().record.as_ref().map(|r| r.1.run_id.clone());
+    }
+
+    /// The number of distinct actions completed in this run.
+    pub fn completed_count(&self) -> usize {
+        let &(ref lock, _) = &*self.shared;
+        return lock
+            .lock()
+            .unwrap()
+            .status
+            .values()
+            .filter(|s| **s == PupNodeStatus::Completed)
+            .count();
+    }
+}
@@ -25,7 +25,7 @@ impl PupTask {
         name: &str,
         path: P,
     ) -> Result<PupTask, PupError> {
-        let manifest_path = join(&context.tasks, path);
+        let manifest_path = join(context.task_root(path.as_ref()), path);
         match PupManifest::try_from(&manifest_path) {
             Ok(manifest) => {
                 let task = PupTask {
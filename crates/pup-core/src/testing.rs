@@ -1,8 +1,10 @@
 use crate::utils::path::join;
 use crate::PupProcess;
 use std::collections::HashMap;
-use std::env::current_exe;
-use std::path::PathBuf;
+use std::env::{current_exe, temp_dir};
+use std::fs;
+use std::process;
+use std::path::{Path, PathBuf};
 
 pub fn test_fixture() -> PupProcess {
     let root = test_context_process_path();
@@ -34,3 +36,22 @@ pub fn test_context_folder() -> PathBuf {
         .join("sample");
     return test_data_folder.canonicalize().unwrap();
 }
+
+/// Return a path in the temp folder for a test to write output to
+pub fn test_output_path(name: &str) -> PathBuf {
+    return join(temp_dir(), format!("pup-test-{}-{}", process::id(), name));
+}
+
+/// Copy a folder and everything in it, eg. a fixture that a test writes to
+pub fn copy_folder(source: &Path, target: &Path) {
+    fs::create_dir_all(target).unwrap();
+    for entry in fs::read_dir(source).unwrap() {
+        let entry = entry.unwrap();
+        let target_path = target.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_folder(&entry.path(), &target_path);
+        } else {
+            fs::copy(entry.path(), &target_path).unwrap();
+        }
+    }
+}
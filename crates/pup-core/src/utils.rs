@@ -1 +1,6 @@
+pub mod duration;
+pub mod env_file;
+pub mod hash;
 pub mod path;
+pub mod secrets;
+pub mod yaml;
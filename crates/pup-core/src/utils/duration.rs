@@ -0,0 +1,92 @@
+use crate::{PupError, PupErrorType};
+use std::time::Duration;
+
+/// Parse a duration like 10m, 30s, 1h30m or 500ms. A plain number is a number of seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, PupError> {
+    let trimmed = value.trim();
+    if let Ok(seconds) = trimmed.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+
+    let mut total = Duration::from_millis(0);
+    let mut rest = trimmed;
+    while rest.len() > 0 {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let unit_end = rest[digits..]
+            .find(|c: char| c.is_ascii_digit())
+            .map(|i| digits + i)
+            .unwrap_or(rest.len());
+        let amount = match rest[..digits].parse::<u64>() {
+            Ok(a) => a,
+            Err(_) => return Err(invalid_duration(value)),
+        };
+        total += match &rest[digits..unit_end] {
+            "ms" => Duration::from_millis(amount),
+            "s" => Duration::from_secs(amount),
+            "m" => Duration::from_secs(amount * 60),
+            "h" => Duration::from_secs(amount * 60 * 60),
+            _ => return Err(invalid_duration(value)),
+        };
+        rest = &rest[unit_end..];
+    }
+
+    if trimmed.len() == 0 {
+        return Err(invalid_duration(value));
+    }
+    return Ok(total);
+}
+
+/// Format a duration in the same units parse_duration accepts, eg. 1h30m
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let millis = duration.subsec_millis();
+    if seconds == 0 {
+        return format!("{}ms", millis);
+    }
+    let mut rtn = String::new();
+    if seconds >= 3600 {
+        rtn.push_str(&format!("{}h", seconds / 3600));
+    }
+    if seconds % 3600 >= 60 {
+        rtn.push_str(&format!("{}m", (seconds % 3600) / 60));
+    }
+    if seconds % 60 > 0 {
+        rtn.push_str(&format!("{}s", seconds % 60));
+    }
+    if millis > 0 {
+        rtn.push_str(&format!("{}ms", millis));
+    }
+    return rtn;
+}
+
+fn invalid_duration(value: &str) -> PupError {
+    return PupError::with_message(
+        PupErrorType::InvalidDuration,
+        &format!("Invalid duration '{}'; use eg. 30s, 10m or 1h30m", value),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_duration, parse_duration};
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10 minutes").is_err());
+        assert!(parse_duration("m").is_err());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(5400)), "1h30m");
+        assert_eq!(format_duration(Duration::from_millis(250)), "250ms");
+        assert_eq!(format_duration(Duration::from_millis(1500)), "1s500ms");
+    }
+}
@@ -0,0 +1,165 @@
+use crate::utils::path;
+use crate::{PupError, PupErrorType};
+use serde_yaml;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Resolve an env file path; relative paths are relative to MANIFEST_HOME
+pub fn resolve(global_env: &HashMap<String, String>, file: &str) -> PathBuf {
+    let file_path = Path::new(file);
+    if file_path.is_absolute() {
+        return PathBuf::from(file_path);
+    }
+    return match global_env.get("MANIFEST_HOME") {
+        Some(home) => path::join(home, file_path),
+        None => PathBuf::from(file_path),
+    };
+}
+
+/// Read an env file; .yml and .yaml files are a map of key: value,
+/// anything else is a .env file of KEY=VALUE lines.
+/// Values are returned as is, so they may still contain templates.
+pub fn read_env_file(env_file_path: &Path) -> Result<HashMap<String, String>, PupError> {
+    let mut raw = String::new();
+    File::open(env_file_path)
+        .and_then(|mut fp| fp.read_to_string(&mut raw))
+        .map_err(|err| {
+            return PupError::with_error(
+                PupErrorType::InvalidEnvFile,
+                &format!("Unable to read env file: {}: {}", path::display(env_file_path), err),
+                err,
+            );
+        })?;
+
+    let is_yaml = match env_file_path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => ext == "yml" || ext == "yaml",
+        None => false,
+    };
+    if is_yaml {
+        if raw.trim().len() == 0 {
+            return Ok(HashMap::new());
+        }
+        return serde_yaml::from_str(&raw).map_err(|err| {
+            return PupError::with_error(
+                PupErrorType::InvalidEnvFile,
+                &format!("Invalid env file: {}: {}", path::display(env_file_path), err),
+                err,
+            );
+        });
+    }
+
+    return parse_dotenv(&raw).map_err(|(line, message)| {
+        return PupError::with_message(
+            PupErrorType::InvalidEnvFile,
+            &format!("Invalid env file: {}: line {}: {}", path::display(env_file_path), line, message),
+        );
+    });
+}
+
+/// Parse KEY=VALUE lines, ignoring blank lines, # comments and any 'export ' prefix.
+/// Values may be 'single quoted', taken literally, or "double quoted", with \n, \" and \\ escapes.
+fn parse_dotenv(raw: &str) -> Result<HashMap<String, String>, (usize, String)> {
+    let mut rtn = HashMap::new();
+    for (index, line) in raw.lines().enumerate() {
+        let line = line.trim();
+        if line.len() == 0 || line.starts_with('#') {
+            continue;
+        }
+        let line = if line.starts_with("export ") { line["export ".len()..].trim_start() } else { line };
+        let split = match line.find('=') {
+            Some(s) => s,
+            None => return Err((index + 1, format!("expected KEY=VALUE, found '{}'", line))),
+        };
+        let key = line[..split].trim();
+        if key.len() == 0 || key.contains(char::is_whitespace) {
+            return Err((index + 1, format!("invalid key '{}'", key)));
+        }
+        let value = parse_value(line[split + 1..].trim()).map_err(|message| (index + 1, message))?;
+        rtn.insert(key.to_string(), value);
+    }
+    return Ok(rtn);
+}
+
+fn parse_value(value: &str) -> Result<String, String> {
+    if value.starts_with('\'') {
+        return match value[1..].find('\'') {
+            Some(end) => Ok(value[1..end + 1].to_string()),
+            None => Err(format!("unterminated quote in {}", value)),
+        };
+    }
+    if value.starts_with('"') {
+        let mut rtn = String::new();
+        let mut chars = value[1..].chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return Ok(rtn),
+                '\\' => match chars.next() {
+                    Some('n') => rtn.push('\n'),
+                    Some(other) => rtn.push(other),
+                    None => break,
+                },
+                other => rtn.push(other),
+            }
+        }
+        return Err(format!("unterminated quote in {}", value));
+    }
+
+    // Unquoted values end at an inline comment
+    return Ok(match value.find(" #") {
+        Some(comment) => value[..comment].trim_end().to_string(),
+        None => value.to_string(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_dotenv, read_env_file, resolve};
+    use crate::errors::PupErrorType;
+    use crate::testing::test_context_process_path;
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_dotenv() {
+        let env = parse_dotenv(
+            "# shared settings\n\
+             export TARGET=release\n\
+             NAME = pup # the name\n\
+             GREETING=\"hello\\n\\\"world\\\"\"\n\
+             LITERAL='{{not}} # rendered'\n\
+             EMPTY=\n",
+        )
+        .unwrap();
+        assert_eq!(env.len(), 5);
+        assert_eq!(env["TARGET"], "release");
+        assert_eq!(env["NAME"], "pup");
+        assert_eq!(env["GREETING"], "hello\n\"world\"");
+        assert_eq!(env["LITERAL"], "{{not}} # rendered");
+        assert_eq!(env["EMPTY"], "");
+    }
+
+    #[test]
+    fn test_parse_invalid_dotenv() {
+        assert_eq!(parse_dotenv("FOO=1\nBAR\n").err().unwrap().0, 2);
+        assert_eq!(parse_dotenv("FOO=\"open\n").err().unwrap().0, 1);
+        assert_eq!(parse_dotenv("MY KEY=1\n").err().unwrap().0, 1);
+    }
+
+    #[test]
+    fn test_read_env_files() {
+        let mut global_env = HashMap::new();
+        let home = test_context_process_path().parent().unwrap().display().to_string();
+        global_env.insert(String::from("MANIFEST_HOME"), home);
+
+        let shared = read_env_file(&resolve(&global_env, "envs/shared.env")).unwrap();
+        assert_eq!(shared["layered"], "from-file");
+
+        let build = read_env_file(&resolve(&global_env, "envs/build.yml")).unwrap();
+        assert_eq!(build["BUILD_ENV_FILE"], "loaded");
+
+        let err = read_env_file(Path::new("envs/missing.env")).err().unwrap();
+        assert_eq!(err.error_type, PupErrorType::InvalidEnvFile);
+    }
+}
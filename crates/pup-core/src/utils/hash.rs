@@ -0,0 +1,93 @@
+use crate::utils::path::join;
+use crate::PupError;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Return a stable hex digest for an env map, independent of key order.
+pub fn hash_env(env: &HashMap<String, String>) -> String {
+    let mut keys: Vec<&String> = env.keys().collect();
+    keys.sort();
+
+    let mut hasher = Sha1::new();
+    for key in keys {
+        hasher.input(key.as_bytes());
+        hasher.input(&[0]);
+        hasher.input(env[key].as_bytes());
+        hasher.input(&[0]);
+    }
+    return format!("{:x}", hasher.result());
+}
+
+/// Return a stable hex digest for an ordered list of lines.
+pub fn hash_lines(lines: &[String]) -> String {
+    let mut hasher = Sha1::new();
+    for line in lines {
+        hasher.input(line.as_bytes());
+        hasher.input(&[0]);
+    }
+    return format!("{:x}", hasher.result());
+}
+
+/// Return a stable hex digest of the contents of a file.
+pub fn hash_file(file: &Path) -> Result<String, PupError> {
+    let mut content = Vec::new();
+    File::open(file)?.read_to_end(&mut content)?;
+    let mut hasher = Sha1::new();
+    hasher.input(&content);
+    return Ok(format!("{:x}", hasher.result()));
+}
+
+/// Return a stable hex digest of the relative path and contents of every file in a folder.
+pub fn hash_folder(folder: &Path) -> Result<String, PupError> {
+    return hash_folder_except(folder, &[]);
+}
+
+/// Return a stable hex digest of the relative path and contents of every file in a folder,
+/// ignoring any file that is, or is inside, one of the 'excluded' paths.
+pub fn hash_folder_except(folder: &Path, excluded: &[PathBuf]) -> Result<String, PupError> {
+    let mut files = Vec::new();
+    collect_files(folder, folder, excluded, &mut files)?;
+    files.sort();
+
+    let mut hasher = Sha1::new();
+    for relative in files.iter() {
+        let mut content = Vec::new();
+        File::open(join(folder, relative))?.read_to_end(&mut content)?;
+        hasher.input(relative.as_bytes());
+        hasher.input(&[0]);
+        hasher.input(&content);
+        hasher.input(&[0]);
+    }
+    return Ok(format!("{:x}", hasher.result()));
+}
+
+fn collect_files(
+    root: &Path,
+    folder: &Path,
+    excluded: &[PathBuf],
+    files: &mut Vec<String>,
+) -> Result<(), PupError> {
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        if excluded.iter().any(|e| path.starts_with(e)) {
+            continue;
+        }
+        if path.is_dir() {
+            collect_files(root, &path, excluded, files)?;
+            continue;
+        }
+        if let Ok(relative) = path.strip_prefix(root) {
+            // Use the same separator on every platform, so the hash is portable
+            let parts: Vec<String> = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect();
+            files.push(parts.join("/"));
+        }
+    }
+    return Ok(());
+}
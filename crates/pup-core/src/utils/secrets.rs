@@ -0,0 +1,254 @@
+use crate::logger::get_logger;
+use crate::{PupError, PupErrorType};
+use base_logging::Level;
+use glob::Pattern;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// What a secret value is replaced with in logs and reports
+pub const MASK: &str = "****";
+
+/// The shortest value that is masked in free text. Shorter values, eg. 1, true or dev, would also
+/// mask unrelated text like counts, paths and timestamps, so they are left visible there with a warning.
+/// Wherever an env is shown, the value of a secret key is redacted whatever its length.
+pub const MIN_SECRET_LENGTH: usize = 6;
+
+/// The secrets of a single process.
+/// Cloning a set of secrets returns a handle to the same underlying set.
+#[derive(Clone, Debug, Default)]
+pub struct PupSecrets {
+    shared: Arc<Mutex<PupSecretValues>>,
+}
+
+/// The env keys that hold secrets, and every value known to be, or derived from, a secret
+#[derive(Debug, Default)]
+struct PupSecretValues {
+    patterns: Vec<Pattern>,
+    values: Vec<String>,
+
+    /// The keys and templates already warned about for being too short to mask
+    warned: HashSet<String>,
+}
+
+lazy_static! {
+    static ref ACTIVE: Mutex<PupSecrets> = Mutex::new(PupSecrets::new());
+}
+
+impl PupSecrets {
+    pub fn new() -> PupSecrets {
+        return PupSecrets::default();
+    }
+
+    /// Mask these secrets in every log and report from now on.
+    /// This replaces the secrets of any process loaded before, so they don't leak into this one.
+    pub fn activate(&self) {
+        *ACTIVE.lock().unwrap() = self.clone();
+    }
+
+    /// Treat every env key matching one of these names or glob patterns, eg. *_TOKEN, as a secret
+    pub fn add_patterns(&self, patterns: &[String]) -> Result<(), PupError> {
+        let mut compiled = Vec::new();
+        for pattern in patterns.iter() {
+            compiled.push(Pattern::new(pattern).map_err(|err| {
+                return PupError::with_message(
+                    PupErrorType::InvalidGlobPattern,
+                    &format!("Invalid secret pattern: {}: {}", pattern, err),
+                );
+            })?);
+        }
+        self.shared.lock().unwrap().patterns.extend(compiled);
+        return Ok(());
+    }
+
+    /// Is the value of this env key a secret?
+    pub fn is_secret_key(&self, key: &str) -> bool {
+        return self
+            .shared
+            .lock()
+            .unwrap()
+            .patterns
+            .iter()
+            .any(|p| p.matches(key));
+    }
+
+    /// Mask this value from now on. Empty values are ignored.
+    /// Returns false if the value is too short to mask safely, so it is not masked.
+    pub fn add_value(&self, value: &str) -> bool {
+        if value.len() == 0 {
+            return true;
+        }
+        if value.chars().count() < MIN_SECRET_LENGTH {
+            return false;
+        }
+        let mut secrets = self.shared.lock().unwrap();
+        if !secrets.values.iter().any(|v| v == value) {
+            secrets.values.push(value.to_string());
+
+            // Longest first, so a value containing another is masked whole
+            secrets.values.sort_by(|a, b| b.len().cmp(&a.len()));
+        }
+        return true;
+    }
+
+    /// Mask the value of every secret key in an env
+    pub fn track_env(&self, env: &HashMap<String, String>) {
+        for (key, value) in env.iter() {
+            if self.is_secret_key(key) && !self.add_value(value) {
+                self.warn_once(&format!("the value of {}", key));
+            }
+        }
+    }
+
+    /// Mask the result of rendering 'source' if it refers to any secret in 'env'
+    pub fn track_render(&self, source: &str, env: &HashMap<String, String>, rendered: &str) {
+        if !source.contains("{{") {
+            return;
+        }
+        let uses_secret = env.iter().any(|(key, value)| {
+            value.len() > 0
+                && source.contains(key.as_str())
+                && (self.is_secret_key(key) || self.is_secret_value(value))
+        });
+        if uses_secret && !self.add_value(rendered) {
+            self.warn_once(&format!("the value rendered from '{}'", source));
+        }
+    }
+
+    /// The value of an env key as it may be shown: the mask for a secret key, whatever
+    /// the length of its value, or the value with any secrets in it masked
+    pub fn redact(&self, key: &str, value: &str) -> String {
+        if self.is_secret_key(key) {
+            return MASK.to_string();
+        }
+        return self.mask(value);
+    }
+
+    /// Replace every secret value in some text with the mask
+    pub fn mask(&self, text: &str) -> String {
+        let secrets = self.shared.lock().unwrap();
+        let mut rtn = text.to_string();
+        for value in secrets.values.iter() {
+            if rtn.contains(value.as_str()) {
+                rtn = rtn.replace(value.as_str(), MASK);
+            }
+        }
+        return rtn;
+    }
+
+    /// Replace every secret value in the strings of a json value with the mask.
+    /// This must happen before the value is serialized, as escaping a secret would hide it.
+    pub fn mask_json(&self, value: &mut Value) {
+        match value {
+            Value::String(text) => *text = self.mask(text),
+            Value::Array(items) => items.iter_mut().for_each(|item| self.mask_json(item)),
+            Value::Object(fields) => fields.values_mut().for_each(|field| self.mask_json(field)),
+            _ => {}
+        }
+    }
+
+    fn is_secret_value(&self, value: &str) -> bool {
+        return self.shared.lock().unwrap().values.iter().any(|v| v == value);
+    }
+
+    fn warn_once(&self, what: &str) {
+        if !self.shared.lock().unwrap().warned.insert(what.to_string()) {
+            return;
+        }
+        let mut logger = get_logger();
+        logger.log(
+            Level::Warn,
+            format!(
+                "Secret not masked in output: {} is shorter than {} characters",
+                what, MIN_SECRET_LENGTH
+            ),
+        );
+    }
+}
+
+/// The secrets of the active process
+pub fn active() -> PupSecrets {
+    return ACTIVE.lock().unwrap().clone();
+}
+
+/// Replace every secret value of the active process in some text with the mask
+pub fn mask(text: &str) -> String {
+    return active().mask(text);
+}
+
+/// The value of an env key of the active process as it may be shown
+pub fn redact(key: &str, value: &str) -> String {
+    return active().redact(key, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PupSecrets;
+    use serde_json;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_mask_secret_values() {
+        let secrets = PupSecrets::new();
+        secrets.add_patterns(&[String::from("*_TOKEN")]).unwrap();
+        let mut env = HashMap::new();
+        env.insert(String::from("API_TOKEN"), String::from("token-value-1"));
+        env.insert(String::from("NAME"), String::from("visible-value-1"));
+        secrets.track_env(&env);
+        assert_eq!(
+            secrets.mask("using token-value-1 for visible-value-1"),
+            "using **** for visible-value-1"
+        );
+
+        // Values rendered from a secret are secret too
+        secrets.track_render("Bearer:{{API_TOKEN}}", &env, "Bearer:token-value-1");
+        secrets.track_render("{{NAME}}!", &env, "visible-value-1!");
+        assert_eq!(secrets.mask("Bearer:token-value-1"), "****");
+        assert_eq!(secrets.mask("visible-value-1!"), "visible-value-1!");
+    }
+
+    #[test]
+    fn test_short_secret_values_are_not_masked() {
+        let secrets = PupSecrets::new();
+        secrets.add_patterns(&[String::from("*_TOKEN")]).unwrap();
+        let mut env = HashMap::new();
+        env.insert(String::from("SHORT_TOKEN"), String::from("1"));
+        secrets.track_env(&env);
+        assert!(!secrets.add_value("true"));
+        assert_eq!(secrets.mask("1 step took 1.0001s"), "1 step took 1.0001s");
+
+        // They are still redacted wherever the env is shown
+        assert_eq!(secrets.redact("SHORT_TOKEN", "1"), "****");
+        assert_eq!(secrets.redact("COUNT", "1"), "1");
+    }
+
+    #[test]
+    fn test_secrets_are_separate() {
+        let first = PupSecrets::new();
+        let second = PupSecrets::new();
+        assert!(first.add_value("first-secret"));
+        assert_eq!(second.mask("first-secret"), "first-secret");
+        assert_eq!(first.clone().mask("first-secret"), "****");
+    }
+
+    #[test]
+    fn test_mask_json_before_escaping() {
+        let secrets = PupSecrets::new();
+        assert!(secrets.add_value("p<ss&wo\"rd\\"));
+        let mut value = serde_json::json!({
+            "reason": "login with p<ss&wo\"rd\\ failed",
+            "steps": [{ "error": "p<ss&wo\"rd\\" }],
+            "count": 1,
+        });
+        secrets.mask_json(&mut value);
+        let raw = serde_json::to_string(&value).unwrap();
+        assert!(!raw.contains("ss&wo"));
+        assert_eq!(value["reason"], "login with **** failed");
+        assert_eq!(value["steps"][0]["error"], "****");
+    }
+
+    #[test]
+    fn test_invalid_secret_pattern() {
+        assert!(PupSecrets::new().add_patterns(&[String::from("[")]).is_err());
+    }
+}
@@ -0,0 +1,35 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_yaml;
+use serde_yaml::Value;
+
+/// The keys a type reads, found by serializing a minimal instance of it
+pub fn known_keys<T: DeserializeOwned + Serialize>(minimal: &str) -> Vec<String> {
+    let instance: T = serde_yaml::from_str(minimal).unwrap();
+    return match serde_yaml::to_value(&instance) {
+        Ok(Value::Mapping(m)) => m.iter().filter_map(|(k, _)| k.as_str().map(|s| s.to_string())).collect(),
+        _ => Vec::new(),
+    };
+}
+
+/// Remove every key in a mapping that isn't one of 'known', and return the keys removed
+pub fn remove_unknown_keys(value: &mut Value, known: &[String]) -> Vec<String> {
+    let mapping = match value.as_mapping_mut() {
+        Some(m) => m,
+        None => return Vec::new(),
+    };
+    let unknown: Vec<Value> = mapping
+        .iter()
+        .map(|(k, _)| k.clone())
+        .filter(|k| !known.iter().any(|name| Some(name.as_str()) == k.as_str()))
+        .collect();
+    for key in unknown.iter() {
+        mapping.remove(key);
+    }
+    return unknown.iter().map(|k| k.as_str().unwrap_or("").to_string()).collect();
+}
+
+/// The value under 'key' in a mapping, if there is one
+pub fn field_mut<'a>(value: &'a mut Value, key: &str) -> Option<&'a mut Value> {
+    return value.as_mapping_mut().and_then(|m| m.get_mut(&Value::from(key)));
+}
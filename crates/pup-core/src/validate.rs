@@ -0,0 +1,523 @@
+use crate::errors::PupErrorType;
+use crate::manifest::{PupManifest, PupManifestRetry, PupManifestStep, PupManifestVersion};
+use crate::process::{PupProcess, PupProcessManifest, PupProcessProfile};
+use crate::utils::duration::parse_duration;
+use crate::utils::path;
+use crate::utils::path::join;
+use crate::utils::yaml::{field_mut, known_keys, remove_unknown_keys};
+use handlebars::Template;
+use serde_yaml;
+use serde_yaml::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+/// A single problem found while validating a process, with where it is
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PupProblem {
+    /// The file the problem is in
+    pub path: PathBuf,
+
+    /// The line in the file, from 1, if it is known
+    pub line: Option<usize>,
+
+    /// What is wrong
+    pub message: String,
+}
+
+impl fmt::Display for PupProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self.line {
+            Some(line) => write!(f, "{}:{}: {}", path::display(&self.path), line, self.message),
+            None => write!(f, "{}: {}", path::display(&self.path), self.message),
+        };
+    }
+}
+
+/// The result of checking a process and every task it can see
+#[derive(Debug, Default)]
+pub struct PupValidation {
+    /// Every problem found, in the order they were found
+    pub problems: Vec<PupProblem>,
+
+    /// The number of task manifests checked
+    pub tasks: usize,
+
+    /// The number of versions checked, across every task
+    pub versions: usize,
+
+    /// Every worker used, and the first manifest that uses it
+    actions: HashMap<String, PathBuf>,
+}
+
+impl PupValidation {
+    /// Check the process manifest, the root tasks, and every version of every task
+    /// under the tasks roots, collecting every problem instead of stopping at the first.
+    pub fn check(process: &PupProcess) -> PupValidation {
+        let mut validation = PupValidation::default();
+        validation.check_process(process);
+        for root in process.context.tasks.iter() {
+            for task_folder in find_task_folders(root) {
+                validation.check_task(process, &task_folder);
+            }
+        }
+
+        // Each worker is only reported once, however many tasks use it
+        let mut actions: Vec<(String, PathBuf)> = validation.actions.drain().collect();
+        actions.sort();
+        for (action, manifest_path) in actions {
+            validation.check_worker(process, &action, &manifest_path);
+        }
+        return validation;
+    }
+
+    /// Did the process pass every check?
+    pub fn is_ok(&self) -> bool {
+        return self.problems.len() == 0;
+    }
+
+    fn check_process(&mut self, process: &PupProcess) {
+        let raw = match fs::read_to_string(&process.path) {
+            Ok(r) => r,
+            Err(err) => {
+                self.problem(&process.path, None, format!("Unable to read process manifest: {}", err));
+                return;
+            }
+        };
+
+        // The process was loaded leniently, so report any unknown keys here
+        let root = YamlNode::parse(&raw);
+        let root = root.as_ref();
+        if let Ok(mut value) = serde_yaml::from_str::<Value>(&raw) {
+            let keys = known_keys::<PupProcessManifest>("{}");
+            self.check_keys(&process.path, root, &mut value, &keys, "process manifest");
+            let profile_keys = known_keys::<PupProcessProfile>("{}");
+            if let Some(profiles) = field_mut(&mut value, "profiles").and_then(|p| p.as_mapping_mut()) {
+                for (name, profile) in profiles.iter_mut() {
+                    let node = child(child(root, "profiles"), name.as_str().unwrap_or(""));
+                    self.check_keys(&process.path, node, profile, &profile_keys, "profile");
+                }
+            }
+        }
+
+        for task in process.manifest.tasks.iter() {
+            if let Some(message) = resolve_problem(process, task) {
+                let line = child(root, "tasks")
+                    .and_then(|tasks| tasks.items.iter().find(|t| t.value == *task))
+                    .map(|t| t.line);
+                self.problem(&process.path, line, format!("Root task {} does not resolve: {}", task, message));
+            }
+        }
+        let mut profiles: Vec<&String> = process.manifest.profiles.keys().collect();
+        profiles.sort();
+        for name in profiles {
+            if let Err(err) = process.manifest.resolve_profile(name) {
+                let line = key_line(child(root, "profiles"), name);
+                self.problem(&process.path, line, err.error_detail);
+            }
+        }
+        for (key, value) in sorted(&process.manifest.environment) {
+            let line = key_line(child(root, "environment"), key);
+            self.check_template(&process.path, line, value);
+        }
+    }
+
+    fn check_task(&mut self, process: &PupProcess, task_folder: &Path) {
+        let manifest_path = join(task_folder, "manifest.yml");
+        self.tasks += 1;
+        let raw = match fs::read_to_string(&manifest_path) {
+            Ok(r) => r,
+            Err(err) => {
+                self.problem(&manifest_path, None, format!("Unable to read manifest: {}", err));
+                return;
+            }
+        };
+        let mut value: Value = match serde_yaml::from_str(&raw) {
+            Ok(v) => v,
+            Err(err) => {
+                let line = err.location().map(|l| l.line());
+                self.problem(&manifest_path, line, format!("Invalid manifest: {}", err));
+                return;
+            }
+        };
+
+        // Report unknown keys, then drop them so the rest of the manifest can still be checked
+        let root = YamlNode::parse(&raw);
+        let root = root.as_ref();
+        self.check_manifest_keys(&manifest_path, root, &mut value);
+        let mut manifest: PupManifest = match serde_yaml::from_value(value) {
+            Ok(m) => m,
+            Err(err) => {
+                self.problem(&manifest_path, None, format!("Invalid manifest: {}", err));
+                return;
+            }
+        };
+        if let Err(err) = manifest.validate(task_folder) {
+            self.problem(&manifest_path, None, err.error_detail);
+        }
+        self.actions
+            .entry(manifest.action.clone())
+            .or_insert(manifest_path.clone());
+
+        // Every version must be unique and every step in it must be usable
+        let mut seen = HashSet::new();
+        for (index, version) in manifest.versions.iter().enumerate() {
+            self.versions += 1;
+            let node = item(child(root, "versions"), index);
+            if !seen.insert(version.version.to_string()) {
+                let line = key_line(node, "version");
+                self.problem(&manifest_path, line, format!("Duplicate version {}", version.version));
+            }
+            self.check_version(process, &manifest_path, node, version);
+        }
+    }
+
+    fn check_version(
+        &mut self,
+        process: &PupProcess,
+        manifest_path: &Path,
+        node: Option<&YamlNode>,
+        version: &PupManifestVersion,
+    ) {
+        for list in ["inputs", "outputs"].iter() {
+            let patterns = if *list == "inputs" { &version.inputs } else { &version.outputs };
+            for (index, pattern) in patterns.iter().enumerate() {
+                let line = item(child(node, list), index).map(|n| n.line);
+                self.check_template(manifest_path, line, pattern);
+            }
+        }
+        for (index, step) in version.steps.iter().enumerate() {
+            self.check_step(process, manifest_path, item(child(node, "steps"), index), step);
+        }
+        for (index, step) in version.finally.iter().enumerate() {
+            self.check_step(process, manifest_path, item(child(node, "finally"), index), step);
+        }
+    }
+
+    fn check_step(
+        &mut self,
+        process: &PupProcess,
+        manifest_path: &Path,
+        node: Option<&YamlNode>,
+        step: &PupManifestStep,
+    ) {
+        if let Some(message) = resolve_problem(process, &step.step) {
+            let line = key_line(node, "step");
+            self.problem(manifest_path, line, format!("Step {} does not resolve: {}", step.step, message));
+        }
+        for (key, value) in sorted(&step.environment) {
+            self.check_template(manifest_path, key_line(child(node, "environment"), key), value);
+        }
+        self.check_template(manifest_path, key_line(node, "skip"), &step.skip);
+        self.check_template(manifest_path, key_line(node, "if"), &step.if_marker);
+        self.check_template(manifest_path, key_line(node, "env_file"), &step.env_file);
+        if step.timeout != "" {
+            if let Err(err) = parse_duration(&step.timeout) {
+                self.problem(manifest_path, key_line(node, "timeout"), err.error_detail);
+            }
+        }
+        if let Some(ref retry) = step.retry {
+            if retry.delay != "" {
+                if let Err(err) = parse_duration(&retry.delay) {
+                    let line = key_line(child(node, "retry"), "delay");
+                    self.problem(manifest_path, line, err.error_detail);
+                }
+            }
+        }
+        for (index, undo) in step.undo.iter().enumerate() {
+            self.check_step(process, manifest_path, item(child(node, "undo"), index), undo);
+        }
+    }
+
+    fn check_template(&mut self, file: &Path, line: Option<usize>, template: &str) {
+        if !template.contains("{{") {
+            return;
+        }
+        if let Err(err) = Template::compile(template) {
+            self.problem(file, line, format!("Invalid template '{}': {}", template, err.reason));
+        }
+    }
+
+    fn check_worker(&mut self, process: &PupProcess, action: &str, manifest_path: &Path) {
+        match process.context.load_worker(action) {
+            Ok(worker) => {
+                if !is_executable(&worker.path) {
+                    self.problem(&worker.path, None, format!("Worker {} is not executable", action));
+                }
+            }
+            Err(err) => {
+                let root = YamlNode::parse(&fs::read_to_string(manifest_path).unwrap_or_default());
+                self.problem(manifest_path, key_line(root.as_ref(), "action"), err.error_detail);
+            }
+        }
+    }
+
+    fn check_manifest_keys(&mut self, manifest_path: &Path, root: Option<&YamlNode>, value: &mut Value) {
+        let manifest_keys = known_keys::<PupManifest>("{action: x, versions: []}");
+        let version_keys = known_keys::<PupManifestVersion>("{version: x}");
+
+        self.check_keys(manifest_path, root, value, &manifest_keys, "manifest");
+        for (index, version) in sequence(value, "versions").into_iter().enumerate() {
+            let node = item(child(root, "versions"), index);
+            self.check_keys(manifest_path, node, version, &version_keys, "version");
+            for list in ["steps", "finally"].iter() {
+                for (index, step) in sequence(version, list).into_iter().enumerate() {
+                    self.check_step_keys(manifest_path, item(child(node, list), index), step);
+                }
+            }
+        }
+    }
+
+    fn check_step_keys(&mut self, manifest_path: &Path, node: Option<&YamlNode>, step: &mut Value) {
+        let step_keys = known_keys::<PupManifestStep>("{step: x}");
+        let retry_keys = known_keys::<PupManifestRetry>("{}");
+        self.check_keys(manifest_path, node, step, &step_keys, "step");
+        if let Some(retry) = field_mut(step, "retry") {
+            self.check_keys(manifest_path, child(node, "retry"), retry, &retry_keys, "retry");
+        }
+        for (index, undo) in sequence(step, "undo").into_iter().enumerate() {
+            self.check_step_keys(manifest_path, item(child(node, "undo"), index), undo);
+        }
+    }
+
+    /// Report, and remove, every key in a mapping that isn't one of 'known'
+    fn check_keys(&mut self, file: &Path, node: Option<&YamlNode>, value: &mut Value, known: &[String], what: &str) {
+        for name in remove_unknown_keys(value, known) {
+            let line = key_line(node, &name);
+            self.problem(file, line, format!("Unknown {} key '{}'; expected one of: {}", what, name, known.join(", ")));
+        }
+    }
+
+    fn problem(&mut self, file: &Path, line: Option<usize>, message: String) {
+        self.problems.push(PupProblem {
+            path: PathBuf::from(file),
+            line,
+            message,
+        });
+    }
+}
+
+/// The values in a sequence under 'key' in a mapping, if there are any
+fn sequence<'a>(value: &'a mut Value, key: &str) -> Vec<&'a mut Value> {
+    return match field_mut(value, key) {
+        Some(Value::Sequence(items)) => items.iter_mut().collect(),
+        _ => Vec::new(),
+    };
+}
+
+fn sorted(map: &HashMap<String, String>) -> Vec<(&String, &String)> {
+    let mut rtn: Vec<(&String, &String)> = map.iter().collect();
+    rtn.sort();
+    return rtn;
+}
+
+/// Every folder with a manifest.yml under a tasks root; version folders are not tasks.
+fn find_task_folders(root: &Path) -> Vec<PathBuf> {
+    let mut rtn = Vec::new();
+    let mut entries: Vec<PathBuf> = match fs::read_dir(root) {
+        Ok(e) => e.filter_map(|entry| entry.ok().map(|e| e.path())).collect(),
+        Err(_) => return rtn,
+    };
+    entries.sort();
+    if root.join("manifest.yml").is_file() {
+        rtn.push(PathBuf::from(root));
+    }
+    for entry in entries {
+        if entry.is_dir() && entry.file_name().map(|n| n != "versions").unwrap_or(false) {
+            rtn.extend(find_task_folders(&entry));
+        }
+    }
+    return rtn;
+}
+
+#[cfg(unix)]
+fn is_executable(worker_path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    return match fs::metadata(worker_path) {
+        Ok(m) => m.is_file() && m.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    };
+}
+
+#[cfg(not(unix))]
+fn is_executable(worker_path: &Path) -> bool {
+    return worker_path.is_file();
+}
+
+/// If a task reference doesn't resolve, why not.
+/// A task with a manifest that doesn't parse is not reported here; its own problems are.
+fn resolve_problem(process: &PupProcess, task: &str) -> Option<String> {
+    return match process.context.load_task(task) {
+        Ok(_) => None,
+        Err(ref err) if err.error_type == PupErrorType::InvalidYaml => None,
+        Err(err) => Some(err.error_detail),
+    };
+}
+
+/// A node in a yaml document, with the line it is on.
+/// Parsed yaml values have no positions, so these come from the events of the yaml parser.
+#[derive(Debug, Default)]
+struct YamlNode {
+    /// The line the node starts on, from 1
+    line: usize,
+
+    /// The value, if this is a scalar
+    value: String,
+
+    /// Each key, the line it is on, and its value, if this is a mapping
+    entries: Vec<(String, usize, YamlNode)>,
+
+    /// Each item, if this is a sequence
+    items: Vec<YamlNode>,
+}
+
+impl YamlNode {
+    /// Parse the first document in some yaml, if it is valid
+    fn parse(raw: &str) -> Option<YamlNode> {
+        let mut events = YamlEvents { events: Vec::new() };
+        if Parser::new(raw.chars()).load(&mut events, false).is_err() {
+            return None;
+        }
+        return YamlNode::build(&mut events.events.into_iter());
+    }
+
+    /// Build the next node from a stream of events; None at the end of a mapping or sequence.
+    fn build<I: Iterator<Item = (Event, Marker)>>(events: &mut I) -> Option<YamlNode> {
+        loop {
+            let (event, mark) = events.next()?;
+            let mut node = YamlNode {
+                line: mark.line(),
+                ..YamlNode::default()
+            };
+            match event {
+                Event::Scalar(value, _, _, _) => {
+                    node.value = value;
+                    return Some(node);
+                }
+                Event::Alias(_) => return Some(node),
+                Event::SequenceStart(_) => {
+                    while let Some(next) = YamlNode::build(events) {
+                        node.items.push(next);
+                    }
+                    return Some(node);
+                }
+                Event::MappingStart(_) => {
+                    while let Some(key) = YamlNode::build(events) {
+                        let value = YamlNode::build(events).unwrap_or_default();
+                        node.entries.push((key.value, key.line, value));
+                    }
+                    return Some(node);
+                }
+                Event::SequenceEnd | Event::MappingEnd => return None,
+                _ => {}
+            }
+        }
+    }
+}
+
+struct YamlEvents {
+    events: Vec<(Event, Marker)>,
+}
+
+impl MarkedEventReceiver for YamlEvents {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        self.events.push((event, mark));
+    }
+}
+
+/// The value of a key in a mapping node
+fn child<'a>(node: Option<&'a YamlNode>, key: &str) -> Option<&'a YamlNode> {
+    return node.and_then(|n| n.entries.iter().find(|e| e.0 == key).map(|e| &e.2));
+}
+
+/// The line a key in a mapping node is on
+fn key_line(node: Option<&YamlNode>, key: &str) -> Option<usize> {
+    return node.and_then(|n| n.entries.iter().find(|e| e.0 == key).map(|e| e.1));
+}
+
+/// An item in a sequence node
+fn item(node: Option<&YamlNode>, index: usize) -> Option<&YamlNode> {
+    return node.and_then(|n| n.items.get(index));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{child, item, key_line, PupProblem, PupValidation, YamlNode};
+    use crate::testing::test_context_folder;
+    use crate::utils::path::join;
+    use crate::PupProcess;
+
+    fn find<'a>(validation: &'a PupValidation, needle: &str) -> Vec<&'a PupProblem> {
+        return validation
+            .problems
+            .iter()
+            .filter(|p| p.message.contains(needle))
+            .collect();
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        // Unknown keys fail a normal load, so validate loads leniently to report them
+        assert!(PupProcess::load_from(join(test_context_folder(), "validate.yml"), None).is_err());
+        let process = PupProcess::load_lenient(join(test_context_folder(), "validate.yml"), None, None).unwrap();
+        let validation = PupValidation::check(&process);
+        assert!(!validation.is_ok());
+        assert_eq!(validation.tasks, 3);
+
+        let unknown = find(&validation, "Unknown");
+        let keys: Vec<(&str, Option<usize>)> = unknown
+            .iter()
+            .map(|p| (p.message.split('\'').nth(1).unwrap(), p.line))
+            .collect();
+        assert!(keys.contains(&("stages", Some(4))));
+        assert!(keys.contains(&("descripton", Some(3))));
+        assert!(keys.contains(&("stpe", Some(8))));
+        assert!(keys.contains(&("attempt", Some(13))));
+        assert!(keys.contains(&("verison", Some(17))));
+
+        let duplicate = find(&validation, "Duplicate version 1.0.0");
+        assert_eq!(duplicate.len(), 1);
+        assert_eq!(duplicate[0].line, Some(14));
+
+        let missing = find(&validation, "Step tests.no.such.task does not resolve");
+        assert_eq!(missing[0].line, Some(9));
+        let template = find(&validation, "Invalid template '{{#if FOO}}'");
+        assert_eq!(template[0].line, Some(11));
+        assert_eq!(find(&validation, "InvalidDuration").len(), 1);
+
+        let root = find(&validation, "Root task broken.missing does not resolve");
+        assert_eq!(root[0].line, Some(7));
+        assert_eq!(find(&validation, "Unable to find any worker 'pup-missing'").len(), 1);
+
+        // Problems render as file:line: message
+        assert!(format!("{}", template[0]).contains("keys/manifest.yml:11: Invalid template"));
+
+        // A task with unknown keys is reported once, in its own manifest
+        assert_eq!(find(&validation, "Root task broken.keys").len(), 0);
+    }
+
+    #[test]
+    fn test_yaml_node_lines() {
+        let raw = "steps:\n  - step: goodness\n  - step: good\n    environment:\n      'foo': \"{{bar}}\"\n";
+        let root = YamlNode::parse(raw);
+        let steps = child(root.as_ref(), "steps");
+        assert_eq!(key_line(item(steps, 1), "step"), Some(3));
+        assert_eq!(child(item(steps, 1), "step").unwrap().value, "good");
+        assert_eq!(key_line(child(item(steps, 1), "environment"), "foo"), Some(5));
+        assert_eq!(key_line(item(steps, 2), "step"), None);
+        assert!(YamlNode::parse("steps: [").is_none());
+    }
+
+    #[test]
+    fn test_validate_clean_task() {
+        let process = PupProcess::load_lenient(join(test_context_folder(), "validate.yml"), None, None).unwrap();
+        let validation = PupValidation::check(&process);
+        assert!(validation
+            .problems
+            .iter()
+            .all(|p| !p.path.display().to_string().contains("good")));
+    }
+}
@@ -1,6 +1,8 @@
 use crate::PupArg;
 use ::pup_core::{PupError, PupErrorType};
+use ::pup_core::utils::duration::parse_duration;
 use std::collections::HashMap;
+use std::time::Duration;
 use crate::infrastructure::runner::PupTaskRunner;
 
 pub fn require_key(args: &HashMap<PupArg, String>, required: PupArg) -> Result<(), PupError> {
@@ -22,6 +24,26 @@ pub fn boolean_value(args: &HashMap<PupArg, String>, key: PupArg) -> Result<bool
     return Ok(value != "" && value != "false" && value != "0");
 }
 
+pub fn usize_value(args: &HashMap<PupArg, String>, key: PupArg, default: usize) -> Result<usize, PupError> {
+    if !args.contains_key(&key) {
+        return Ok(default);
+    }
+
+    return args[&key].parse::<usize>().map_err(|_| {
+        PupError::with_message(
+            PupErrorType::InvalidRequest,
+            &format!("Invalid number for {:?}: {}", key, args[&key]),
+        )
+    });
+}
+
+pub fn duration_value(args: &HashMap<PupArg, String>, key: PupArg) -> Result<Option<Duration>, PupError> {
+    if !args.contains_key(&key) {
+        return Ok(None);
+    }
+    return Ok(Some(parse_duration(&args[&key])?));
+}
+
 pub fn is_ok(target: &impl PupTaskRunner) -> Result<(), PupError> {
     if !target.ready() {
         return Err(PupError::from(PupErrorType::InvalidRequest));
@@ -8,6 +8,7 @@ use pup_core::{PupError, PupErrorType};
 use std::collections::HashMap;
 use crate::tasks::get_task_runner;
 use pup_core::logger::set_logger_level;
+use pup_core::utils::secrets;
 use base_logging::Level;
 
 #[derive(Debug, PartialEq, Clone, Copy, Eq, Hash)]
@@ -16,6 +17,16 @@ pub enum PupArg {
     ListTaskVersions,
     TaskId,
     DryRun,
+    Jobs,
+    Locked,
+    Resume,
+    Timeout,
+    KeepGoing,
+    Events,
+    Junit,
+    HistoryRunId,
+    Strict,
+    Profile,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -23,6 +34,9 @@ pub enum PupTask {
     ListAvailableTasks,
     ShowExecutionPlan,
     RunTask,
+    WriteLock,
+    ShowHistory,
+    Validate,
 }
 
 pub fn pup_main(task: PupTask, args: HashMap<PupArg, String>) -> Result<(), PupError> {
@@ -34,7 +48,10 @@ pub fn pup_main(task: PupTask, args: HashMap<PupArg, String>) -> Result<(), PupE
             }
             let mut logger = ::pup_core::logger::get_logger();
             logger.log(Level::Debug, format!("Executing task: {:?}", task));
-            return runner.run(&mut logger);
+            return runner.run(&mut logger).map_err(|mut err| {
+                err.error_detail = secrets::mask(&err.error_detail);
+                return err;
+            });
         }
         None => Err(PupError::with_message(
             PupErrorType::InvalidRequest,
@@ -48,12 +65,18 @@ pub fn pup_enable_debug() {
     set_logger_level(Level::Debug);
 }
 
+/// Pass Ctrl-C and SIGTERM on to running workers before exiting.
+pub fn pup_forward_signals() {
+    pup_core::forward_signals_to_workers();
+}
+
 #[cfg(test)]
 mod tests {
     use super::{pup_main, PupArg, PupTask};
     use super::pup_core::testing::test_context_process_path;
     use std::collections::HashMap;
     use pup_core::logger::set_logger_level;
+    use pup_core::PupErrorType;
     use base_logging::Level;
 
     #[test]
@@ -91,6 +114,23 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_show_plan_with_profile()
+    {
+        set_logger_level(Level::Debug);
+        let mut args = HashMap::new();
+        args.insert(PupArg::ProcessManifestPath, String::from(test_context_process_path().to_str().unwrap()));
+        args.insert(PupArg::TaskId, String::from("tests.builds.deployment"));
+        args.insert(PupArg::Profile, String::from("prod"));
+
+        let result = pup_main(PupTask::ShowExecutionPlan, args.clone());
+        assert!(result.is_ok());
+
+        args.insert(PupArg::Profile, String::from("no-such-profile"));
+        let result = pup_main(PupTask::ShowExecutionPlan, args);
+        assert!(result.is_err());
+    }
+
     #[test]
     fn test_run_task_dry_run()
     {
@@ -115,4 +155,48 @@ mod tests {
         // This will fail because the demo tasks are invalid.
         let _ = pup_main(PupTask::RunTask, args);
     }
+
+    #[test]
+    fn test_run_task_reports_run_failure_over_report_failure()
+    {
+        set_logger_level(Level::Debug);
+        let mut args = HashMap::new();
+        let process_path = test_context_process_path();
+        args.insert(PupArg::ProcessManifestPath, String::from(process_path.to_str().unwrap()));
+        args.insert(PupArg::TaskId, String::from("tests.failures.mixed#0.0.1"));
+
+        // The report can't be written inside a file
+        args.insert(PupArg::Junit, String::from(process_path.join("report.xml").to_str().unwrap()));
+
+        let result = pup_main(PupTask::RunTask, args);
+        assert_eq!(result.err().unwrap().error_type, PupErrorType::WorkerFailed);
+    }
+
+    #[test]
+    fn test_validate()
+    {
+        set_logger_level(Level::Debug);
+        let mut args = HashMap::new();
+        let process_path = test_context_process_path().with_file_name("validate.yml");
+        args.insert(PupArg::ProcessManifestPath, String::from(process_path.to_str().unwrap()));
+
+        // The validation fixtures are full of deliberate mistakes
+        let result = pup_main(PupTask::Validate, args);
+        assert_eq!(result.err().unwrap().error_type, PupErrorType::ValidationFailed);
+    }
+
+    #[test]
+    fn test_show_history()
+    {
+        set_logger_level(Level::Debug);
+        let mut args = HashMap::new();
+        args.insert(PupArg::ProcessManifestPath, String::from(test_context_process_path().to_str().unwrap()));
+
+        let result = pup_main(PupTask::ShowHistory, args.clone());
+        assert!(result.is_ok());
+
+        args.insert(PupArg::HistoryRunId, String::from("no-such-run"));
+        let result = pup_main(PupTask::ShowHistory, args);
+        assert!(result.is_err());
+    }
 }
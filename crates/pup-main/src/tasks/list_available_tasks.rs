@@ -15,6 +15,7 @@ pub fn list_available_tasks() -> TaskRunnerListAvailable {
     return TaskRunnerListAvailable {
         ok: false,
         path: PathBuf::new(),
+        profile: None,
         show_versions: false,
     };
 }
@@ -22,6 +23,7 @@ pub fn list_available_tasks() -> TaskRunnerListAvailable {
 pub struct TaskRunnerListAvailable {
     ok: bool,
     path: PathBuf,
+    profile: Option<String>,
     show_versions: bool,
 }
 
@@ -30,6 +32,7 @@ impl PupTaskRunner for TaskRunnerListAvailable {
         validation::require_key(&args, PupArg::ProcessManifestPath)?;
 
         self.path = PathBuf::from(args.get(&PupArg::ProcessManifestPath).unwrap());
+        self.profile = args.get(&PupArg::Profile).map(|p| p.to_string());
         self.ok = true;
         self.show_versions = validation::boolean_value(&args, PupArg::ListTaskVersions)?;
 
@@ -44,21 +47,20 @@ impl PupTaskRunner for TaskRunnerListAvailable {
         validation::is_ok(self)?;
 
         logger.log(Level::Debug, format!("Reading: {}", path::display(&self.path)));
-        let mut process = PupProcess::load_from(&self.path, None)?;
+        let mut process = PupProcess::load_with_profile(&self.path, None, self.profile.as_ref().map(|p| p.as_str()))?;
 
         logger.log(Level::Debug, format!("Found {} tasks", &process.manifest.tasks.len()));
         for task in &process.manifest.tasks.clone() {
             if self.show_versions {
                 match process.task(&task) {
-                    Ok((task_ref, _)) => {
-                        for version in 0..task_ref.manifest.versions.len() {
-                            if  version ==    task_ref.manifest.versions.len() -1 {
-                                logger.log(Level::Info, format!("{}#{} (default)", task, task_ref.manifest.versions[version].version));
+                    Ok((task_ref, default_version)) => {
+                        for version in task_ref.manifest.sorted_versions() {
+                            if version.version == default_version.version {
+                                logger.log(Level::Info, format!("{}#{} (default)", task, version.version));
                             }
                             else {
-                                logger.log(Level::Info, format!("{}#{}", task, task_ref.manifest.versions[version].version));    
+                                logger.log(Level::Info, format!("{}#{}", task, version.version));
                             }
-                            
                         }
                     }
                     Err(err) => {
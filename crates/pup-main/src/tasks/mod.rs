@@ -1,12 +1,18 @@
 mod list_available_tasks;
 mod show_execution_plan;
 mod run_task;
+mod write_lock;
+mod show_history;
+mod validate;
 
 use crate::PupTask;
 use crate::tasks::list_available_tasks::list_available_tasks;
 use crate::infrastructure::runner::PupTaskRunner;
 use crate::tasks::show_execution_plan::show_execution_plan;
 use crate::tasks::run_task::run_task;
+use crate::tasks::write_lock::write_lock;
+use crate::tasks::show_history::show_history;
+use crate::tasks::validate::validate;
 
 pub fn get_task_runner(task: PupTask) -> Option<Box<PupTaskRunner>> {
     if task == PupTask::ListAvailableTasks {
@@ -18,5 +24,14 @@ pub fn get_task_runner(task: PupTask) -> Option<Box<PupTaskRunner>> {
     if task == PupTask::RunTask {
         return Some(Box::new(run_task()));
     }
+    if task == PupTask::WriteLock {
+        return Some(Box::new(write_lock()));
+    }
+    if task == PupTask::ShowHistory {
+        return Some(Box::new(show_history()));
+    }
+    if task == PupTask::Validate {
+        return Some(Box::new(validate()));
+    }
     return None;
 }
\ No newline at end of file
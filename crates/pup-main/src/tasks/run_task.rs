@@ -6,26 +6,47 @@ use ::pup_core::utils::path;
 use crate::infrastructure::validation;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::Duration;
 use ::base_logging::{Logger, Level};
 use ::pup_core::PupProcess;
+use ::pup_core::PupLockMode;
 use crate::infrastructure::runner::PupTaskRunner;
 use std::error::Error;
 use ::pup_core::PupActionOptions;
+use ::pup_core::PupEventSink;
 
 pub fn run_task() -> TaskRunnerRunTask {
     return TaskRunnerRunTask {
         ok: false,
         path: PathBuf::new(),
+        profile: None,
         task: String::new(),
+        locked: false,
+        strict: false,
+        resume: false,
+        timeout: None,
+        keep_going: false,
+        events: None,
+        junit: None,
         dry_run: false,
+        jobs: 1,
     };
 }
 
 pub struct TaskRunnerRunTask {
     ok: bool,
     path: PathBuf,
+    profile: Option<String>,
     task: String,
+    locked: bool,
+    strict: bool,
+    resume: bool,
+    timeout: Option<Duration>,
+    keep_going: bool,
+    events: Option<String>,
+    junit: Option<PathBuf>,
     dry_run: bool,
+    jobs: usize,
 }
 
 impl PupTaskRunner for TaskRunnerRunTask {
@@ -34,8 +55,17 @@ impl PupTaskRunner for TaskRunnerRunTask {
         validation::require_key(&args, PupArg::TaskId)?;
 
         self.path = PathBuf::from(args.get(&PupArg::ProcessManifestPath).unwrap());
+        self.profile = args.get(&PupArg::Profile).map(|p| p.to_string());
         self.task = args.get(&PupArg::TaskId).unwrap().to_string();
+        self.locked = validation::boolean_value(&args, PupArg::Locked)?;
+        self.strict = validation::boolean_value(&args, PupArg::Strict)?;
+        self.resume = validation::boolean_value(&args, PupArg::Resume)?;
+        self.timeout = validation::duration_value(&args, PupArg::Timeout)?;
+        self.keep_going = validation::boolean_value(&args, PupArg::KeepGoing)?;
+        self.events = args.get(&PupArg::Events).map(|e| e.to_string());
+        self.junit = args.get(&PupArg::Junit).map(|j| PathBuf::from(j));
         self.dry_run = validation::boolean_value(&args, PupArg::DryRun)?;
+        self.jobs = validation::usize_value(&args, PupArg::Jobs, 1)?;
         self.ok = true;
 
         return Ok(());
@@ -49,16 +79,69 @@ impl PupTaskRunner for TaskRunnerRunTask {
         validation::is_ok(self)?;
 
         logger.log(Level::Debug, format!("Reading: {:?}", path::display(&self.path)));
-        let mut process = PupProcess::load_from(&self.path, None)?;
+        let mut process = PupProcess::load_with_profile(&self.path, None, self.profile.as_ref().map(|p| p.as_str()))?;
+        if self.locked {
+            process.lock_mode = PupLockMode::Locked;
+        }
+        if self.strict {
+            process.context.strict = true;
+        }
 
         logger.log(Level::Debug, format!("Opening: {}", self.task));
         match process.runner(&self.task) {
             Ok(mut runner) => {
-                match runner.run(PupActionOptions {
+                if self.resume {
+                    let record = process.last_run()?;
+                    let skipped = runner.resume(&record)?;
+                    logger.log(Level::Info, format!("Resuming run {}: {} steps already succeeded", record.run_id, skipped));
+                }
+                runner.use_fingerprints(&process.fingerprints_path())?;
+                let events = match self.events {
+                    Some(ref target) => Some(PupEventSink::open(target)?),
+                    None => None,
+                };
+                let mut log_folder = None;
+                let mut run_id = None;
+                if !self.dry_run {
+                    let id = runner.record_to(&process.run_record_path())?;
+                    logger.log(Level::Debug, format!("Run: {}", id));
+                    log_folder = Some(process.run_folder(&id));
+                    run_id = Some(id);
+                }
+                let result = runner.run(PupActionOptions {
                     dry_run: self.dry_run,
                     args: Vec::new(),
-                }) {
-                    Ok(_) => Ok(()),
+                    jobs: self.jobs,
+                    timeout: self.timeout,
+                    keep_going: self.keep_going,
+                    log_folder,
+                    events,
+                    ..PupActionOptions::default()
+                });
+
+                // The history and report are written even if the run failed.
+                // Failing to write them is reported, but never hides why the run failed.
+                let mut write_error = None;
+                if let Some(ref id) = run_id {
+                    if let Some(record) = runner.history_record(id) {
+                        if let Err(err) = process.history().append(&record) {
+                            logger.log(Level::Warn, format!("Unable to write run history: {}", err.description()));
+                            write_error = write_error.or(Some(err));
+                        }
+                    }
+                }
+                if let Some(ref report_path) = self.junit {
+                    logger.log(Level::Debug, format!("Writing report: {}", path::display(report_path)));
+                    if let Err(err) = runner.write_junit(report_path) {
+                        logger.log(Level::Warn, format!("Unable to write report: {}", err.description()));
+                        write_error = write_error.or(Some(err));
+                    }
+                }
+                match result {
+                    Ok(_) => match write_error {
+                        Some(err) => Err(err),
+                        None => Ok(()),
+                    },
                     Err(err) => {
                         logger.log(Level::Debug, format!("Failed: {}", err.description()));
                         Err(err)
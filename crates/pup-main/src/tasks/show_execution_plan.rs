@@ -8,6 +8,7 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 use ::base_logging::{Logger, Level};
 use ::pup_core::PupProcess;
+use ::pup_core::PupLockMode;
 use crate::infrastructure::runner::PupTaskRunner;
 use std::error::Error;
 
@@ -15,14 +16,20 @@ pub fn show_execution_plan() -> TaskRunnerShowPlan {
     return TaskRunnerShowPlan {
         ok: false,
         path: PathBuf::new(),
+        profile: None,
         task: String::new(),
+        locked: false,
+        strict: false,
     };
 }
 
 pub struct TaskRunnerShowPlan {
     ok: bool,
     path: PathBuf,
+    profile: Option<String>,
     task: String,
+    locked: bool,
+    strict: bool,
 }
 
 impl PupTaskRunner for TaskRunnerShowPlan {
@@ -31,7 +38,10 @@ impl PupTaskRunner for TaskRunnerShowPlan {
         validation::require_key(&args, PupArg::TaskId)?;
 
         self.path = PathBuf::from(args.get(&PupArg::ProcessManifestPath).unwrap());
+        self.profile = args.get(&PupArg::Profile).map(|p| p.to_string());
         self.task = args.get(&PupArg::TaskId).unwrap().to_string();
+        self.locked = validation::boolean_value(&args, PupArg::Locked)?;
+        self.strict = validation::boolean_value(&args, PupArg::Strict)?;
         self.ok = true;
 
         return Ok(());
@@ -45,7 +55,13 @@ impl PupTaskRunner for TaskRunnerShowPlan {
         validation::is_ok(self)?;
 
         logger.log(Level::Debug, format!("Reading: {:?}", path::display(&self.path)));
-        let mut process = PupProcess::load_from(&self.path, None)?;
+        let mut process = PupProcess::load_with_profile(&self.path, None, self.profile.as_ref().map(|p| p.as_str()))?;
+        if self.locked {
+            process.lock_mode = PupLockMode::Locked;
+        }
+        if self.strict {
+            process.context.strict = true;
+        }
 
         logger.log(Level::Debug, format!("Opening: {}", self.task));
         match process.runner(&self.task) {
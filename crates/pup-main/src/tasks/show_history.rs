@@ -0,0 +1,127 @@
+extern crate pup_core;
+
+use crate::PupArg;
+use ::pup_core::PupError;
+use ::pup_core::utils::path;
+use ::pup_core::utils::duration::format_duration;
+use crate::infrastructure::validation;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use ::base_logging::{Logger, Level};
+use ::pup_core::{PupHistoryRecord, PupProcess, PupStepOutcome};
+use crate::infrastructure::runner::PupTaskRunner;
+
+/// The number of runs to list, most recent first
+const RECENT_RUNS: usize = 20;
+
+pub fn show_history() -> TaskRunnerShowHistory {
+    return TaskRunnerShowHistory {
+        ok: false,
+        path: PathBuf::new(),
+        run_id: None,
+    };
+}
+
+pub struct TaskRunnerShowHistory {
+    ok: bool,
+    path: PathBuf,
+    run_id: Option<String>,
+}
+
+impl PupTaskRunner for TaskRunnerShowHistory {
+    fn prepare(&mut self, args: HashMap<PupArg, String>) -> Result<(), PupError> {
+        validation::require_key(&args, PupArg::ProcessManifestPath)?;
+
+        self.path = PathBuf::from(args.get(&PupArg::ProcessManifestPath).unwrap());
+        self.run_id = args.get(&PupArg::HistoryRunId).map(|id| id.to_string());
+        self.ok = true;
+
+        return Ok(());
+    }
+
+    fn ready(&self) -> bool {
+        return self.ok;
+    }
+
+    fn run(&mut self, logger: &mut Logger) -> Result<(), PupError> {
+        validation::is_ok(self)?;
+
+        logger.log(Level::Debug, format!("Reading: {:?}", path::display(&self.path)));
+        let process = PupProcess::load_from(&self.path, None)?;
+        let history = process.history();
+
+        match self.run_id {
+            Some(ref run_id) => {
+                let record = history.find(run_id)?;
+                show_run(&record, logger);
+            }
+            None => {
+                let runs = history.runs()?;
+                if runs.len() == 0 {
+                    logger.log(Level::Info, format!("No runs recorded yet"));
+                }
+                for record in runs.iter().rev().take(RECENT_RUNS) {
+                    let failed = match record.failed_step {
+                        Some(ref step) => format!(" at {}", step),
+                        None => String::new(),
+                    };
+                    logger.log(Level::Info, format!(
+                        "{}  {}  {}  {}  {}{}",
+                        record.run_id,
+                        record.started,
+                        milliseconds(record.duration_ms),
+                        record.tasks.join(", "),
+                        status(record.success),
+                        failed
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn show_run(record: &PupHistoryRecord, logger: &mut Logger) {
+    logger.log(Level::Info, format!("Run: {}", record.run_id));
+    logger.log(Level::Info, format!("Tasks: {}", record.tasks.join(", ")));
+    logger.log(Level::Info, format!("Started: {}", record.started));
+    logger.log(Level::Info, format!("Finished: {} ({})", record.finished, milliseconds(record.duration_ms)));
+    logger.log(Level::Info, format!("Result: {}", status(record.success)));
+    if let Some(ref step) = record.failed_step {
+        logger.log(Level::Info, format!("Failed step: {}", step));
+    }
+    for step in record.steps.iter() {
+        let outcome = match step.outcome {
+            PupStepOutcome::Passed => "ok",
+            PupStepOutcome::Failed => "FAILED",
+            PupStepOutcome::Skipped => "skipped",
+            PupStepOutcome::NotRun => "not run",
+        };
+        let timing = match step.duration_ms {
+            Some(ms) => format!(" ({})", milliseconds(ms)),
+            None => String::new(),
+        };
+        let reason = match step.reason {
+            Some(ref r) => format!(": {}", r),
+            None => String::new(),
+        };
+        logger.log(Level::Info, format!(
+            "{}{} {}{}{}",
+            "  ".repeat(step.depth),
+            step.step,
+            outcome,
+            timing,
+            reason
+        ));
+    }
+}
+
+fn status(success: bool) -> &'static str {
+    return if success { "succeeded" } else { "FAILED" };
+}
+
+fn milliseconds(ms: i64) -> String {
+    return format_duration(Duration::from_millis(if ms > 0 { ms as u64 } else { 0 }));
+}
@@ -0,0 +1,67 @@
+extern crate pup_core;
+
+use crate::PupArg;
+use ::pup_core::{PupError, PupErrorType};
+use ::pup_core::utils::path;
+use crate::infrastructure::validation;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use ::base_logging::{Logger, Level};
+use ::pup_core::{PupProcess, PupValidation};
+use crate::infrastructure::runner::PupTaskRunner;
+
+pub fn validate() -> TaskRunnerValidate {
+    return TaskRunnerValidate {
+        ok: false,
+        path: PathBuf::new(),
+        profile: None,
+    };
+}
+
+pub struct TaskRunnerValidate {
+    ok: bool,
+    path: PathBuf,
+    profile: Option<String>,
+}
+
+impl PupTaskRunner for TaskRunnerValidate {
+    fn prepare(&mut self, args: HashMap<PupArg, String>) -> Result<(), PupError> {
+        validation::require_key(&args, PupArg::ProcessManifestPath)?;
+
+        self.path = PathBuf::from(args.get(&PupArg::ProcessManifestPath).unwrap());
+        self.profile = args.get(&PupArg::Profile).map(|p| p.to_string());
+        self.ok = true;
+
+        return Ok(());
+    }
+
+    fn ready(&self) -> bool {
+        return self.ok;
+    }
+
+    fn run(&mut self, logger: &mut Logger) -> Result<(), PupError> {
+        validation::is_ok(self)?;
+
+        logger.log(Level::Debug, format!("Reading: {:?}", path::display(&self.path)));
+        let process = PupProcess::load_lenient(&self.path, None, self.profile.as_ref().map(|p| p.as_str()))?;
+
+        let result = PupValidation::check(&process);
+        for problem in result.problems.iter() {
+            logger.log(Level::Warn, format!("{}", problem));
+        }
+        logger.log(Level::Info, format!(
+            "Checked {} tasks and {} versions: {} problems",
+            result.tasks,
+            result.versions,
+            result.problems.len()
+        ));
+
+        if !result.is_ok() {
+            return Err(PupError::with_message(
+                PupErrorType::ValidationFailed,
+                &format!("{} problems in {}", result.problems.len(), path::display(&self.path)),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+extern crate pup_core;
+
+use crate::PupArg;
+use ::pup_core::PupError;
+use ::pup_core::utils::path;
+use crate::infrastructure::validation;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use ::base_logging::{Logger, Level};
+use ::pup_core::PupProcess;
+use crate::infrastructure::runner::PupTaskRunner;
+use std::error::Error;
+
+pub fn write_lock() -> TaskRunnerWriteLock {
+    return TaskRunnerWriteLock {
+        ok: false,
+        path: PathBuf::new(),
+        profile: None,
+    };
+}
+
+pub struct TaskRunnerWriteLock {
+    ok: bool,
+    path: PathBuf,
+    profile: Option<String>,
+}
+
+impl PupTaskRunner for TaskRunnerWriteLock {
+    fn prepare(&mut self, args: HashMap<PupArg, String>) -> Result<(), PupError> {
+        validation::require_key(&args, PupArg::ProcessManifestPath)?;
+
+        self.path = PathBuf::from(args.get(&PupArg::ProcessManifestPath).unwrap());
+        self.profile = args.get(&PupArg::Profile).map(|p| p.to_string());
+        self.ok = true;
+
+        return Ok(());
+    }
+
+    fn ready(&self) -> bool {
+        return self.ok;
+    }
+
+    fn run(&mut self, logger: &mut Logger) -> Result<(), PupError> {
+        validation::is_ok(self)?;
+
+        logger.log(Level::Debug, format!("Reading: {:?}", path::display(&self.path)));
+        let mut process = PupProcess::load_with_profile(&self.path, None, self.profile.as_ref().map(|p| p.as_str()))?;
+
+        match process.write_lock() {
+            Ok(lock) => {
+                for (task, root) in lock.tasks.iter() {
+                    logger.log(Level::Debug, format!("Locked {} steps for {}", root.steps.len(), task));
+                }
+                logger.log(Level::Info, format!("Wrote: {}", path::display(&process.lockfile)));
+                Ok(())
+            }
+            Err(err) => {
+                logger.log(Level::Debug, format!("Failed: {}", err.description()));
+                Err(err)
+            }
+        }
+    }
+}
@@ -3,13 +3,13 @@ extern crate pup_main;
 
 use std::env;
 use getopts::Options;
-use pup_main::{pup_enable_debug, pup_main, PupArg, PupTask};
+use pup_main::{pup_enable_debug, pup_forward_signals, pup_main, PupArg, PupTask};
 use std::collections::HashMap;
 use std::process;
 use std::error::Error;
 
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} FILE [options]\n     : If FILE is ommitted, 'manifest.yml' will be used.", program);
+    let brief = format!("Usage: {} FILE [options]\n     : If FILE is ommitted, 'manifest.yml' will be used.\n\n       {} history [show RUN] [FILE]\n     : List recent runs, or show the steps of one run.\n\n       {} validate [FILE]\n     : Check every task, version and step, and report every problem found.", program, program, program);
     
     print!("{}", opts.usage(&brief));
 }
@@ -24,6 +24,7 @@ fn err_failure(error: &str) {
 }
 
 fn main() {
+    pup_forward_signals();
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
 
@@ -33,6 +34,16 @@ fn main() {
     opts.optflag("d", "dryrun", "dry-run the task, showing debug information");
     opts.optflag("e", "execute", "execute the task");
     opts.optflag("v", "verbose", "use verbose logging");
+    opts.optopt("j", "jobs", "the number of independent steps to run at once", "N");
+    opts.optflag("", "write-lock", "resolve every task and write the versions used to pup.lock");
+    opts.optflag("", "locked", "fail if the resolved versions do not match pup.lock");
+    opts.optopt("", "profile", "use the named profile from the process manifest, eg. staging", "NAME");
+    opts.optflag("", "strict", "fail if a step template uses an undefined variable");
+    opts.optflag("", "resume", "resume the last run, skipping the steps that already succeeded");
+    opts.optflag("k", "keep-going", "keep running every step that doesn't depend on a failed step");
+    opts.optopt("", "timeout", "the longest the whole run may take, eg. 30s, 10m or 1h30m", "DURATION");
+    opts.optopt("", "events", "write a json event for each step of the run to FILE, or - for stdout (other output then goes to stderr)", "FILE");
+    opts.optopt("", "junit", "write a JUnit XML report of the run to FILE", "FILE");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
@@ -42,8 +53,28 @@ fn main() {
         }
     };
 
-    let process_manifest = if !matches.free.is_empty() {
-        matches.free[0].clone()
+    // History is a subcommand; the manifest comes after it
+    let is_history = matches.free.first().map(|f| f == "history").unwrap_or(false);
+    let is_validate = matches.free.first().map(|f| f == "validate").unwrap_or(false);
+    let mut free = matches.free.clone();
+    let mut history_run_id = None;
+    if is_history {
+        free.remove(0);
+        if free.first().map(|f| f == "show").unwrap_or(false) {
+            if free.len() < 2 {
+                err_bad_usage("history show requires a run id", &program, opts);
+                return;
+            }
+            history_run_id = Some(free[1].clone());
+            free.drain(0..2);
+        }
+    }
+    if is_validate {
+        free.remove(0);
+    }
+
+    let process_manifest = if !free.is_empty() {
+        free[0].clone()
     } else {
         // Use default manifest
         "manifest.yml".to_string()
@@ -61,11 +92,73 @@ fn main() {
 
     let mut args: HashMap<PupArg, String> = HashMap::new();
     args.insert(PupArg::ProcessManifestPath, process_manifest);
+    if let Some(profile) = matches.opt_str("profile") {
+        args.insert(PupArg::Profile, profile);
+    }
+
+    // Validate
+    if is_validate {
+        match pup_main(PupTask::Validate, args) {
+            Ok(_) => process::exit(0),
+            Err(err) => {
+                err_failure(err.description());
+                process::exit(1)
+            }
+        };
+    }
+
+    // Run history
+    if is_history {
+        if let Some(run_id) = history_run_id {
+            args.insert(PupArg::HistoryRunId, run_id);
+        }
+        match pup_main(PupTask::ShowHistory, args) {
+            Ok(_) => process::exit(0),
+            Err(err) => {
+                err_failure(err.description());
+                process::exit(1)
+            }
+        };
+    }
 
+    // Write lockfile
+    if matches.opt_present("write-lock") {
+        match pup_main(PupTask::WriteLock, args) {
+            Ok(_) => process::exit(0),
+            Err(err) => {
+                err_failure(err.description());
+                process::exit(1)
+            }
+        };
+    }
 
     // Execute
     if matches.opt_present("t") {
         args.insert(PupArg::TaskId, matches.opt_str("t").unwrap());
+        if matches.opt_present("j") {
+            args.insert(PupArg::Jobs, matches.opt_str("j").unwrap());
+        }
+        if matches.opt_present("locked") {
+            args.insert(PupArg::Locked, "1".to_string());
+        }
+        if matches.opt_present("strict") {
+            args.insert(PupArg::Strict, "1".to_string());
+        }
+        if matches.opt_present("resume") {
+            args.insert(PupArg::Resume, "1".to_string());
+        }
+        if matches.opt_present("k") {
+            args.insert(PupArg::KeepGoing, "1".to_string());
+        }
+        if matches.opt_present("timeout") {
+            args.insert(PupArg::Timeout, matches.opt_str("timeout").unwrap());
+        }
+        if matches.opt_present("events") {
+            args.insert(PupArg::Events, matches.opt_str("events").unwrap());
+        }
+        if matches.opt_present("junit") {
+            args.insert(PupArg::Junit, matches.opt_str("junit").unwrap());
+        }
 
         // Dryrun
         if matches.opt_present("d") {